@@ -1,9 +1,15 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::path::PathBuf;
 
+use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
 use env_logger::Env;
+use fgoxide::io::Io;
+use log::info;
 
+use crate::tools::repeat_counter::{collect_counts, DownsampleOpts, QcFilters};
 use crate::utils::built_info;
 
 /// Detect microsatellite instability from a matched tumor-normal pair
@@ -61,15 +67,236 @@ pub struct Opts {
     #[clap(long, default_value = "3.0", display_order = 7)]
     pub outlier_standard_deviation: f64,
 
+    /// Summed step-wise distance between the normal and tumor repeat-length distributions above
+    /// which a locus is called unstable.
+    #[clap(long, default_value = "0.4", display_order = 8)]
+    pub distance_threshold: f64,
+
+    /// Fraction of unstable loci above which the sample is called MSI-H rather than MSS.
+    #[clap(long, default_value = "0.2", display_order = 8)]
+    pub msi_threshold: f64,
+
+    /// Equalize per-locus normal/tumor coverage by randomly subsampling the deeper sample down to
+    /// the shallower sample's surviving read count (or to `--downsample-target`, if set) before
+    /// the distributions are compared.
+    #[clap(long, display_order = 8)]
+    pub downsample: bool,
+
+    /// Fixed target depth to subsample both samples to at a locus, overriding the per-locus
+    /// normal/tumor minimum. Only takes effect when `--downsample` is set.
+    #[clap(long, display_order = 8)]
+    pub downsample_target: Option<u64>,
+
+    /// Seed for the random number generator used by `--downsample`, so that calls are reproducible.
+    #[clap(long, default_value = "42", display_order = 8)]
+    pub seed: u64,
+
     /// The number of threads to use
     #[clap(long, default_value = "1", display_order = 3)]
     pub threads: u64,
 }
 
-// Run index
+/// The MSI call for a sample, based on the fraction of unstable loci.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Mss,
+    MsiH,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Mss => write!(f, "MSS"),
+            Status::MsiH => write!(f, "MSI-H"),
+        }
+    }
+}
+
+/// Cleans up a repeat-length histogram before it is scored: first discards any length supported
+/// by fewer than `min_repeat_reads` reads (removing stutter/noise singletons), then computes the
+/// read-count-weighted mean and standard deviation of the remaining lengths and discards any
+/// length more than `outlier_standard_deviation` standard deviations from that mean.
+fn filter_outliers(
+    counts: &HashMap<usize, usize>,
+    min_repeat_reads: u64,
+    outlier_standard_deviation: f64,
+) -> HashMap<usize, usize> {
+    let mut filtered: HashMap<usize, usize> = counts
+        .iter()
+        .filter(|(_length, &count)| count as u64 >= min_repeat_reads)
+        .map(|(&length, &count)| (length, count))
+        .collect();
+
+    let total: usize = filtered.values().sum();
+    if total == 0 {
+        return filtered;
+    }
+
+    let mean = filtered
+        .iter()
+        .map(|(&length, &count)| length as f64 * count as f64)
+        .sum::<f64>()
+        / total as f64;
+    let variance = filtered
+        .iter()
+        .map(|(&length, &count)| count as f64 * (length as f64 - mean).powi(2))
+        .sum::<f64>()
+        / total as f64;
+    let std_dev = variance.sqrt();
+
+    if std_dev > 0.0 {
+        filtered.retain(|&length, _count| {
+            (length as f64 - mean).abs() <= outlier_standard_deviation * std_dev
+        });
+    }
+
+    filtered
+}
+
+/// Normalizes a repeat-length histogram (repeat count -> read count) to frequencies, then returns
+/// the summed step-wise difference between `normal` and `tumor`: the union of observed repeat
+/// lengths is used, and for each length the absolute difference of the two normalized frequencies
+/// is summed (not averaged) across all observed lengths.
+fn distance(normal: &HashMap<usize, usize>, tumor: &HashMap<usize, usize>) -> f64 {
+    let normal_total: usize = normal.values().sum();
+    let tumor_total: usize = tumor.values().sum();
+    if normal_total == 0 || tumor_total == 0 {
+        return 0.0;
+    }
+
+    let lengths: HashSet<&usize> = normal.keys().chain(tumor.keys()).collect();
+    lengths
+        .into_iter()
+        .map(|length| {
+            let normal_freq = *normal.get(length).unwrap_or(&0) as f64 / normal_total as f64;
+            let tumor_freq = *tumor.get(length).unwrap_or(&0) as f64 / tumor_total as f64;
+            (normal_freq - tumor_freq).abs()
+        })
+        .sum()
+}
+
+// Run detect
 #[allow(clippy::too_many_lines)]
 pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
-    println!("{:?}", opts);
+    let qc = QcFilters {
+        min_read_mean_base_quality: opts.min_read_mean_base_quality as f64,
+        min_locus_mean_base_quality: opts.min_locus_mean_base_quality as f64,
+        min_read_length: opts.min_read_length as usize,
+        min_locus_coverage: opts.min_locus_coverage as usize,
+    };
+    let downsample = DownsampleOpts {
+        enabled: opts.downsample,
+        target: opts.downsample_target.map(|target| target as usize),
+        seed: opts.seed,
+    };
+    let counts = collect_counts(
+        &opts.normal,
+        &opts.tumor,
+        &opts.bedfile,
+        &opts.genome,
+        opts.threads as usize,
+        &qc,
+        &downsample,
+    )?;
+
+    let io = Io::default();
+    let mut writer = io
+        .new_writer(&opts.output)
+        .with_context(|| format!("Could not open output for writing: {:?}", opts.output))?;
+    writeln!(
+        writer,
+        "chrom\tstart\tend\tnormal_coverage\ttumor_coverage\tdistance\tunstable"
+    )?;
+
+    let mut considered_loci = 0usize;
+    let mut unstable_loci = 0usize;
+    let mut total_distance = 0.0;
+
+    for (locus, histogram) in counts {
+        let mut normal_counts: HashMap<usize, usize> = HashMap::new();
+        let mut tumor_counts: HashMap<usize, usize> = HashMap::new();
+        for (length, by_sample) in &histogram {
+            if let Some(count) = by_sample.get(&true) {
+                normal_counts.insert(*length, *count);
+            }
+            if let Some(count) = by_sample.get(&false) {
+                tumor_counts.insert(*length, *count);
+            }
+        }
+
+        let normal_counts = filter_outliers(
+            &normal_counts,
+            opts.min_repeat_reads,
+            opts.outlier_standard_deviation,
+        );
+        let tumor_counts = filter_outliers(
+            &tumor_counts,
+            opts.min_repeat_reads,
+            opts.outlier_standard_deviation,
+        );
+
+        let normal_coverage: usize = normal_counts.values().sum();
+        let tumor_coverage: usize = tumor_counts.values().sum();
+
+        // Outlier filtering can drop a locus entirely, or push its surviving coverage back below
+        // the minimum; either way it shouldn't contribute a spurious distance to the aggregate.
+        if normal_counts.is_empty()
+            || tumor_counts.is_empty()
+            || (normal_coverage as u64) < opts.min_locus_coverage
+            || (tumor_coverage as u64) < opts.min_locus_coverage
+        {
+            continue;
+        }
+
+        let locus_distance = distance(&normal_counts, &tumor_counts);
+        let unstable = locus_distance > opts.distance_threshold;
+
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\t{}",
+            locus.reference_sequence_name(),
+            locus.start_position(),
+            locus.end_position(),
+            normal_coverage,
+            tumor_coverage,
+            locus_distance,
+            unstable
+        )?;
+
+        considered_loci += 1;
+        total_distance += locus_distance;
+        if unstable {
+            unstable_loci += 1;
+        }
+    }
+
+    let unstable_fraction = if considered_loci == 0 {
+        0.0
+    } else {
+        unstable_loci as f64 / considered_loci as f64
+    };
+    let mean_distance = if considered_loci == 0 {
+        0.0
+    } else {
+        total_distance / considered_loci as f64
+    };
+    let status = if unstable_fraction > opts.msi_threshold {
+        Status::MsiH
+    } else {
+        Status::Mss
+    };
+
+    writeln!(
+        writer,
+        "# loci_considered={}\tunstable_loci={}\tunstable_fraction={:.4}\tmean_distance={:.4}\tstatus={}",
+        considered_loci, unstable_loci, unstable_fraction, mean_distance, status
+    )?;
+
+    info!(
+        "MSI status: {} (unstable fraction {:.4} across {} loci)",
+        status, unstable_fraction, considered_loci
+    );
+
     Ok(())
 }
 
@@ -82,3 +309,77 @@ pub fn setup() -> Opts {
 
     Opts::parse()
 }
+
+#[cfg(test)]
+mod test {
+    use super::distance;
+    use super::filter_outliers;
+    use std::collections::HashMap;
+
+    fn histogram(counts: &[(usize, usize)]) -> HashMap<usize, usize> {
+        counts.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_distance_identical_distributions_is_zero() {
+        let normal = histogram(&[(10, 5), (11, 5)]);
+        let tumor = histogram(&[(10, 5), (11, 5)]);
+        assert!((distance(&normal, &tumor) - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_distance_disjoint_distributions_is_two() {
+        // No shared repeat lengths: every unit of normal frequency mass and every unit of tumor
+        // frequency mass contributes its full absolute difference, summing to 2.0.
+        let normal = histogram(&[(10, 10)]);
+        let tumor = histogram(&[(11, 10)]);
+        assert!((distance(&normal, &tumor) - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_distance_is_summed_not_averaged() {
+        // Three observed lengths, contributing 0.2, 0.0 and 0.2 (|0.6 - 0.4|, |0.2 - 0.2|,
+        // |0.2 - 0.4|): a summed metric gives 0.4; an averaged one would give 0.4 / 3.
+        let normal = histogram(&[(10, 6), (11, 2), (12, 2)]);
+        let tumor = histogram(&[(10, 4), (11, 2), (12, 4)]);
+        assert!((distance(&normal, &tumor) - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_distance_empty_sample_is_zero() {
+        let normal = histogram(&[(10, 5)]);
+        let tumor = HashMap::new();
+        assert!((distance(&normal, &tumor) - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_filter_outliers_drops_singletons_below_min_repeat_reads() {
+        let counts = histogram(&[(10, 10), (11, 10), (12, 1)]);
+        let filtered = filter_outliers(&counts, 3, 3.0);
+        assert_eq!(filtered, histogram(&[(10, 10), (11, 10)]));
+    }
+
+    #[test]
+    fn test_filter_outliers_drops_bins_beyond_std_dev_threshold() {
+        // Lengths 9..=11 are tightly clustered (mean 10); the 10 reads at length 50 are an
+        // overwhelming outlier and should be dropped even though they clear the min_repeat_reads
+        // bar.
+        let counts = histogram(&[(9, 10), (10, 10), (11, 10), (50, 10)]);
+        let filtered = filter_outliers(&counts, 1, 1.0);
+        assert_eq!(filtered, histogram(&[(9, 10), (10, 10), (11, 10)]));
+    }
+
+    #[test]
+    fn test_filter_outliers_keeps_everything_within_threshold() {
+        let counts = histogram(&[(10, 10), (11, 10), (12, 10)]);
+        let filtered = filter_outliers(&counts, 1, 3.0);
+        assert_eq!(filtered, counts);
+    }
+
+    #[test]
+    fn test_filter_outliers_all_bins_dropped_yields_empty() {
+        let counts = histogram(&[(10, 1), (11, 1)]);
+        let filtered = filter_outliers(&counts, 3, 3.0);
+        assert!(filtered.is_empty());
+    }
+}
@@ -6,6 +6,7 @@ use std::hash::Hash;
 use std::thread::JoinHandle;
 use std::{path::PathBuf, str::FromStr};
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
@@ -20,10 +21,14 @@ use noodles::bgzf;
 use noodles::core as noodles_core;
 use noodles::fasta;
 use noodles::sam;
+use noodles::sam::record::cigar::op::Kind;
 use noodles::sam::record::sequence::Base;
 
 use flume::{bounded, unbounded, Receiver, Sender};
 use noodles::core::Position;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
 
 /// Counts repeats in your BAM given a BED of repeats
 #[derive(Parser, Debug, Clone)]
@@ -48,27 +53,95 @@ pub struct Opts {
     // // /// The path to the output
     // // #[clap(short = 'o', long, display_order = 5)]
     // // pub output: PathBuf,
+    /// The minimum average per-base read quality for a read to pass the quality control filters
+    #[clap(long, default_value = "25", display_order = 6)]
+    pub min_read_mean_base_quality: u64,
+
+    /// Minimum average per-base quality for the bases contained within the microsatellite locus.
+    /// Reads that pass the read quality filter (above) will still fail quality control if the
+    /// locus quality scores are too low.
+    #[clap(long, default_value = "30", display_order = 7)]
+    pub min_locus_mean_base_quality: u64,
+
+    /// Minimum read length for a read to pass quality control. Only bases that are not clipped
+    /// will be considered; in other words, soft-clipped or hard-clipped parts of the read do not
+    /// count towards the length.
+    #[clap(long, default_value = "35", display_order = 7)]
+    pub min_read_length: u64,
+
+    /// Minimum coverage (after QC filters) required for each of the normal and tumor samples for a
+    /// locus to be considered in the output.
+    #[clap(long, default_value = "30", display_order = 7)]
+    pub min_locus_coverage: u64,
+
+    /// Equalize per-locus normal/tumor coverage by randomly subsampling the deeper sample down to
+    /// the shallower sample's surviving read count (or to `--downsample-target`, if set) before
+    /// reporting.
+    #[clap(long, display_order = 8)]
+    pub downsample: bool,
+
+    /// Fixed target depth to subsample both samples to at a locus, overriding the per-locus
+    /// normal/tumor minimum. Only takes effect when `--downsample` is set.
+    #[clap(long, display_order = 8)]
+    pub downsample_target: Option<u64>,
+
+    /// Seed for the random number generator used by `--downsample`, so that subsampled output is
+    /// reproducible.
+    #[clap(long, default_value = "42", display_order = 8)]
+    pub seed: u64,
 
-    // // /// The minimum average per-base read quality for a read to pass the quality control filters
-    // // #[clap(long, default_value = "25", display_order = 6)]
-    // // pub min_read_mean_base_quality: u64,
-
-    // // /// Minimum average per-base quality for the bases contained within the microsatellite locus.
-    // // /// Reads that pass the read quality filter (above) will still fail quality control if the
-    // // /// locus quality scores are too low.
-    // // #[clap(long, default_value = "30", display_order = 7)]
-    // // pub min_locus_mean_base_quality: u64,
-
-    // // /// Minimum read length for a read to pass quality control. Only bases that are not clipped
-    // // /// will be considered; in other words, soft-clipped or hard-clipped parts of the read do not
-    // // /// count towards the length.
-    // // #[clap(long, default_value = "35", display_order = 7)]
-    // // pub min_read_length: u64,
     /// The number of threads to use
     #[clap(long, default_value = "1", display_order = 3)]
     pub threads: usize,
 }
 
+/// Quality control thresholds applied to reads (and loci) before they are counted. Shared by the
+/// `repeat-counter` and `detect` subcommands.
+#[derive(Clone, Copy)]
+pub(crate) struct QcFilters {
+    pub(crate) min_read_mean_base_quality: f64,
+    pub(crate) min_locus_mean_base_quality: f64,
+    pub(crate) min_read_length: usize,
+    pub(crate) min_locus_coverage: usize,
+}
+
+/// Tallies, by reason, how many reads were dropped by QC filtering. Shared by all worker threads
+/// and logged at debug level once, after every worker has drained its channel, so users can tune
+/// thresholds.
+#[derive(Default)]
+struct QcTally {
+    read_mean_quality: std::sync::atomic::AtomicUsize,
+    read_length: std::sync::atomic::AtomicUsize,
+    locus_mean_quality: std::sync::atomic::AtomicUsize,
+    tract_not_observed: std::sync::atomic::AtomicUsize,
+}
+
+impl QcTally {
+    fn log(&self) {
+        use std::sync::atomic::Ordering::Relaxed;
+        debug!(
+            "Dropped reads: {} (read mean quality), {} (read length), {} (locus mean quality), {} (tract not fully observed)",
+            self.read_mean_quality.load(Relaxed),
+            self.read_length.load(Relaxed),
+            self.locus_mean_quality.load(Relaxed),
+            self.tract_not_observed.load(Relaxed),
+        );
+    }
+}
+
+/// Coverage-equalization settings applied to each locus histogram after counting, before it is
+/// returned from [`collect_counts`]. Shared by the `repeat-counter` and `detect` subcommands.
+#[derive(Clone, Copy)]
+pub(crate) struct DownsampleOpts {
+    /// If `false`, histograms are returned as counted and the remaining fields are ignored.
+    pub(crate) enabled: bool,
+    /// Fixed target depth to subsample both samples to. If `None`, each locus is subsampled to
+    /// the lesser of its normal and tumor coverage.
+    pub(crate) target: Option<usize>,
+    /// Seed for the per-locus random number generator.
+    pub(crate) seed: u64,
+}
+
 struct WorkerJob {
     locus: bed::Record<4>,
     record: sam::alignment::Record,
@@ -85,16 +158,24 @@ fn get_index(bam: &PathBuf) -> Result<bam::bai::Index> {
     index.with_context(|| format!("Could not open BAM index for BAM: {:?}", bam))
 }
 
-fn query_reads(
+/// Queries `bam_reader` once for the region spanning every locus in `loci` (which must all share a
+/// reference sequence), then dispatches each returned read to every locus in the batch that it
+/// fully flanks. Batching nearby loci into a single query avoids re-reading the overlapping BGZF
+/// blocks a per-locus query would repeat.
+fn query_reads_batch(
     bam_header: &sam::Header,
     bam_reader: &mut bam::Reader<bgzf::Reader<File>>,
     bam_index: &bam::bai::Index,
     is_normal: bool,
-    bed_record: &bed::Record<4>,
-    region: &noodles_core::Region,
+    loci: &[bed::Record<4>],
     bam_record_job_tx: &Sender<WorkerJob>,
 ) {
     let name = if is_normal { "normal" } else { "tumor" };
+    let contig = loci[0].reference_sequence_name();
+    let start = loci.iter().map(bed::Record::start_position).min().unwrap();
+    let end = loci.iter().map(bed::Record::end_position).max().unwrap();
+    let region = noodles_core::Region::new(contig, start..=end);
+
     let query = bam_reader
         .query(bam_header.reference_sequences(), bam_index, &region)
         .with_context(|| format!("Could not query {} BAM for region: {:?}", name, region))
@@ -103,17 +184,234 @@ fn query_reads(
         let bam_record = bam_result
             .with_context(|| format!("Could not parse {} reads in region: {:?}", name, region))
             .unwrap();
-        if !bam_record.flags().is_unmapped()
-            && !bam_record.cigar().is_empty()
-            && bam_record.alignment_start().unwrap() <= bed_record.start_position()
-            && bed_record.end_position() <= bam_record.alignment_end().unwrap()
-        {
-            let job = WorkerJob { locus: bed_record.clone(), record: bam_record, is_normal };
-            bam_record_job_tx.send(job).with_context(|| "Could not send BamRecordJob").unwrap();
+        if bam_record.flags().is_unmapped() || bam_record.cigar().is_empty() {
+            continue;
+        }
+        let (Some(alignment_start), Some(alignment_end)) =
+            (bam_record.alignment_start(), bam_record.alignment_end())
+        else {
+            continue;
+        };
+
+        for locus in loci {
+            if alignment_start <= locus.start_position() && locus.end_position() <= alignment_end {
+                let job = WorkerJob {
+                    locus: locus.clone(),
+                    record: bam_record.clone(),
+                    is_normal,
+                };
+                bam_record_job_tx
+                    .send(job)
+                    .with_context(|| "Could not send BamRecordJob")
+                    .unwrap();
+            }
+        }
+    }
+}
+
+/// Extracts the repeat unit length encoded in a locus name of the form `(UNIT)COUNT`, as written
+/// by the repeat finder. Returns `None` if the name does not start with `(` or has no `)`; the
+/// `COUNT` part is not checked.
+fn motif_length(locus: &bed::Record<4>) -> Option<usize> {
+    let name = locus.name()?.to_string();
+    let close = name.find(')')?;
+    if !name.starts_with('(') {
+        return None;
+    }
+    Some(name[1..close].len())
+}
+
+/// Returns the length of the overlap (inclusive on both ends) between two closed ranges, or zero
+/// if they do not overlap.
+fn overlap(a_start: usize, a_end: usize, b_start: usize, b_end: usize) -> usize {
+    let start = a_start.max(b_start);
+    let end = a_end.min(b_end);
+    if start > end {
+        0
+    } else {
+        end - start + 1
+    }
+}
+
+/// Per-read statistics needed to both count repeat units and apply the QC filters, computed in a
+/// single pass over the CIGAR so the alignment is only walked once per read and locus.
+struct LocusReadStats {
+    /// Number of repeat units the read spans at the locus, or `None` if the tract was not fully
+    /// observed (e.g. a soft-clip lands on the tract boundary) or the locus name does not encode a
+    /// repeat motif.
+    repeat_count: Option<usize>,
+    /// Mean base quality across the entire read (including clipped bases).
+    mean_read_quality: f64,
+    /// Non-clipped read length: the sum of the M/I/=/X CIGAR operation lengths.
+    aligned_length: usize,
+    /// Mean base quality over the aligned bases that fall within the locus, or `None` if no
+    /// aligned base overlapped the locus.
+    mean_locus_quality: Option<f64>,
+}
+
+/// Walks a CIGAR (as `(kind, length)` operations) to derive [`LocusReadStats`] for the tract
+/// `tract_start..=tract_end`, given the read's alignment start and quality scores. Pulled out of
+/// [`locus_read_stats`] as a function over plain values (rather than a `sam::alignment::Record`)
+/// so the CIGAR-walking logic can be unit tested without needing a real BAM-backed record.
+fn compute_locus_stats(
+    tract_start: usize,
+    tract_end: usize,
+    motif_len: Option<usize>,
+    alignment_start: usize,
+    quality_scores: &[u8],
+    cigar: impl Iterator<Item = (Kind, usize)>,
+) -> LocusReadStats {
+    let mean_read_quality = if quality_scores.is_empty() {
+        0.0
+    } else {
+        quality_scores.iter().map(|&q| u64::from(q)).sum::<u64>() as f64
+            / quality_scores.len() as f64
+    };
+
+    let mut ref_pos = alignment_start;
+    let mut read_pos: usize = 0;
+    let mut tract_len: i64 = 0;
+    let mut aligned_length = 0usize;
+    let mut locus_quality_sum: u64 = 0;
+    let mut locus_quality_count: usize = 0;
+    let mut fully_observed = true;
+
+    for (kind, len) in cigar {
+        match kind {
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                let op_end = ref_pos + len - 1;
+                tract_len += overlap(ref_pos, op_end, tract_start, tract_end) as i64;
+                aligned_length += len;
+
+                for offset in 0..len {
+                    let ref_base = ref_pos + offset;
+                    if ref_base < tract_start || ref_base > tract_end {
+                        continue;
+                    }
+                    if let Some(score) = quality_scores.get(read_pos + offset) {
+                        locus_quality_sum += u64::from(*score);
+                        locus_quality_count += 1;
+                    }
+                }
+
+                ref_pos += len;
+                read_pos += len;
+            }
+            Kind::Deletion | Kind::Skip => {
+                let op_end = ref_pos + len - 1;
+                tract_len -= overlap(ref_pos, op_end, tract_start, tract_end) as i64;
+                ref_pos += len;
+            }
+            Kind::Insertion => {
+                // An insertion sits between ref_pos - 1 and ref_pos; it only extends the tract
+                // when it falls strictly inside it.
+                if tract_start < ref_pos && ref_pos <= tract_end {
+                    tract_len += len as i64;
+                }
+                aligned_length += len;
+                read_pos += len;
+            }
+            Kind::SoftClip => {
+                // A clip landing exactly on the tract boundary means the tract was not fully
+                // observed, so the read can't be trusted for this locus.
+                if ref_pos == tract_start || ref_pos == tract_end + 1 {
+                    fully_observed = false;
+                }
+                read_pos += len;
+            }
+            Kind::HardClip | Kind::Pad => {}
+        }
+    }
+
+    let repeat_count = match motif_len {
+        Some(motif_len) if fully_observed && tract_len > 0 => Some(tract_len as usize / motif_len),
+        _ => None,
+    };
+    let mean_locus_quality = if locus_quality_count == 0 {
+        None
+    } else {
+        Some(locus_quality_sum as f64 / locus_quality_count as f64)
+    };
+
+    LocusReadStats {
+        repeat_count,
+        mean_read_quality,
+        aligned_length,
+        mean_locus_quality,
+    }
+}
+
+/// Walks the CIGAR of `record` once to derive [`LocusReadStats`] for `locus`.
+fn locus_read_stats(locus: &bed::Record<4>, record: &sam::alignment::Record) -> LocusReadStats {
+    let motif_len = motif_length(locus);
+    let tract_start = usize::from(locus.start_position());
+    let tract_end = usize::from(locus.end_position());
+
+    let quality_scores: Vec<u8> = record
+        .quality_scores()
+        .as_ref()
+        .iter()
+        .map(|score| u8::from(*score))
+        .collect();
+    let alignment_start = usize::from(record.alignment_start().unwrap());
+    let cigar = record.cigar().iter().map(|op| (op.kind(), op.len()));
+
+    compute_locus_stats(
+        tract_start,
+        tract_end,
+        motif_len,
+        alignment_start,
+        &quality_scores,
+        cigar,
+    )
+}
+
+/// Applies the QC filters in `qc` to a read's [`LocusReadStats`], returning the repeat count if
+/// every filter passes. Otherwise tallies the reason it was dropped in `tally` and returns `None`.
+/// Pulled out of [`qc_and_count`] as a function over `LocusReadStats` (rather than a
+/// `sam::alignment::Record`) so the filtering decisions can be unit tested directly.
+fn qc_decision(qc: &QcFilters, stats: &LocusReadStats, tally: &QcTally) -> Option<usize> {
+    use std::sync::atomic::Ordering::Relaxed;
+
+    if stats.mean_read_quality < qc.min_read_mean_base_quality {
+        tally.read_mean_quality.fetch_add(1, Relaxed);
+        return None;
+    }
+    if stats.aligned_length < qc.min_read_length {
+        tally.read_length.fetch_add(1, Relaxed);
+        return None;
+    }
+    match stats.mean_locus_quality {
+        Some(quality) if quality < qc.min_locus_mean_base_quality => {
+            tally.locus_mean_quality.fetch_add(1, Relaxed);
+            None
         }
+        None => {
+            tally.tract_not_observed.fetch_add(1, Relaxed);
+            None
+        }
+        Some(_) => match stats.repeat_count {
+            Some(count) => Some(count),
+            None => {
+                tally.tract_not_observed.fetch_add(1, Relaxed);
+                None
+            }
+        },
     }
 }
 
+/// Applies the QC filters in `qc` to `record` at `locus`, returning the repeat count if the read
+/// passes every filter. Otherwise tallies the reason it was dropped in `tally` and returns `None`.
+fn qc_and_count(
+    qc: &QcFilters,
+    locus: &bed::Record<4>,
+    record: &sam::alignment::Record,
+    tally: &QcTally,
+) -> Option<usize> {
+    let stats = locus_read_stats(locus, record);
+    qc_decision(qc, &stats, tally)
+}
+
 type AnyhowResult<T> = Result<T, anyhow::Error>;
 
 struct CounterJob {
@@ -122,19 +420,61 @@ struct CounterJob {
     is_normal: bool,
 }
 
+/// A batch of loci, all on the same reference sequence and close enough together to be fetched
+/// with a single BAM query, to be queried in the normal or tumor sample.
 struct BamQueryJob {
-    locus: bed::Record<4>,
-    bam: PathBuf,
-    bai: bam::bai::Index,
-    header: sam::Header,
+    loci: Vec<bed::Record<4>>,
     is_normal: bool,
 }
 
+/// Loci within this many bases of each other are grouped into a single [`BamQueryJob`], since BAI
+/// queries over nearby intervals otherwise re-read overlapping BGZF blocks.
+const BATCH_WINDOW: usize = 1_000;
+
+/// Upper bound on the number of loci grouped into a single [`BamQueryJob`], so one very dense
+/// region doesn't produce an unbounded batch.
+const MAX_BATCH_LOCI: usize = 256;
+
+#[derive(Clone)]
 struct BaiAndHeader {
     bai: bam::bai::Index,
     header: sam::Header,
 }
 
+/// Returns `true` if a locus on `locus_contig` starting at `locus_start` should be folded into the
+/// pending batch rather than flushed and starting a new one: the batch must not yet be at
+/// capacity, must be on the same contig, and `locus_start` must be within [`BATCH_WINDOW`] bases of
+/// the batch's current end.
+fn extends_batch(
+    batch_len: usize,
+    batch_contig: Option<&str>,
+    batch_end: Option<usize>,
+    locus_contig: &str,
+    locus_start: usize,
+) -> bool {
+    batch_len < MAX_BATCH_LOCI
+        && batch_contig == Some(locus_contig)
+        && batch_end.is_some_and(|end| locus_start.saturating_sub(end) <= BATCH_WINDOW)
+}
+
+/// Sends the current batch of loci to be queried in both the normal and tumor sample, then clears
+/// it. A no-op if the batch is empty.
+fn flush_batch(batch: &mut Vec<bed::Record<4>>, tx: &Sender<BamQueryJob>) -> Result<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let loci = std::mem::take(batch);
+    tx.send(BamQueryJob {
+        loci: loci.clone(),
+        is_normal: true,
+    })?;
+    tx.send(BamQueryJob {
+        loci,
+        is_normal: false,
+    })?;
+    Ok(())
+}
+
 impl BaiAndHeader {
     fn new(io: &Io, path: &PathBuf, is_normal: bool) -> BaiAndHeader {
         let name = if is_normal { "normal" } else { "tumor" };
@@ -158,30 +498,122 @@ fn build_contig_to_offset(fasta: &PathBuf) -> HashMap<String, u64> {
     let fai = fasta::fai::read(fai_path.clone())
         .with_context(|| format!("Could not open FASTA index: {:?}", fai_path))
         .unwrap();
-    fai.iter().map(|rec| (rec.name().to_string(), rec.offset())).collect()
+    fai.iter()
+        .map(|rec| (rec.name().to_string(), rec.offset()))
+        .collect()
 }
 
-// Run extract
+/// A per-locus histogram of repeat length (number of repeat units) to read count, broken down by
+/// whether the supporting reads came from the normal or tumor sample.
+pub(crate) type LocusHistogram = HashMap<usize, HashMap<bool, usize>>;
+
+/// Randomly subsamples a repeat-length histogram down to `target` reads, preserving its shape: the
+/// histogram is expanded into one entry per read, shuffled, and truncated. A no-op if `counts`
+/// already has `target` reads or fewer.
+fn subsample_counts(
+    counts: &HashMap<usize, usize>,
+    target: usize,
+    rng: &mut StdRng,
+) -> HashMap<usize, usize> {
+    let total: usize = counts.values().sum();
+    if total <= target {
+        return counts.clone();
+    }
+
+    let mut lengths: Vec<&usize> = counts.keys().collect();
+    lengths.sort();
+    let mut reads: Vec<usize> = Vec::with_capacity(total);
+    for &length in lengths {
+        reads.extend(std::iter::repeat(length).take(counts[&length]));
+    }
+
+    reads.shuffle(rng);
+    reads.truncate(target);
+
+    let mut subsampled = HashMap::new();
+    for length in reads {
+        *subsampled.entry(length).or_insert(0) += 1;
+    }
+    subsampled
+}
+
+/// Splits a locus histogram into its normal and tumor repeat-length distributions, subsamples each
+/// down to `target` reads (or to the lesser of the two, if `target` is `None`), and merges them
+/// back into a histogram.
+fn downsample_locus(
+    histogram: LocusHistogram,
+    target: Option<usize>,
+    rng: &mut StdRng,
+) -> LocusHistogram {
+    let mut normal: HashMap<usize, usize> = HashMap::new();
+    let mut tumor: HashMap<usize, usize> = HashMap::new();
+    for (length, by_sample) in &histogram {
+        if let Some(&count) = by_sample.get(&true) {
+            normal.insert(*length, count);
+        }
+        if let Some(&count) = by_sample.get(&false) {
+            tumor.insert(*length, count);
+        }
+    }
+
+    let target = target.unwrap_or_else(|| {
+        let normal_total: usize = normal.values().sum();
+        let tumor_total: usize = tumor.values().sum();
+        normal_total.min(tumor_total)
+    });
+
+    let normal = subsample_counts(&normal, target, rng);
+    let tumor = subsample_counts(&tumor, target, rng);
+
+    let mut merged: LocusHistogram = HashMap::new();
+    for (length, count) in normal {
+        merged
+            .entry(length)
+            .or_insert_with(HashMap::new)
+            .insert(true, count);
+    }
+    for (length, count) in tumor {
+        merged
+            .entry(length)
+            .or_insert_with(HashMap::new)
+            .insert(false, count);
+    }
+    merged
+}
+
+/// Runs the query/count pipeline: queries `normal` and `tumor` for reads overlapping every locus
+/// in `bedfile`, counts the repeat length each read supports, and returns a per-locus histogram
+/// for each, sorted in genome order. Shared by the `repeat-counter` and `detect` subcommands.
 #[allow(clippy::too_many_lines)]
-pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
+pub(crate) fn collect_counts(
+    normal: &PathBuf,
+    tumor: &PathBuf,
+    bedfile: &PathBuf,
+    genome: &PathBuf,
+    threads: usize,
+    qc: &QcFilters,
+    downsample: &DownsampleOpts,
+) -> Result<Vec<(bed::Record<4>, LocusHistogram)>> {
     let (bam_query_job_tx, bam_query_job_rx): (Sender<BamQueryJob>, Receiver<BamQueryJob>) =
-        flume::bounded(opts.threads * 8);
+        flume::bounded(threads * 8);
 
     let (worker_job_tx, worker_job_rx): (Sender<WorkerJob>, Receiver<WorkerJob>) =
-        flume::bounded(opts.threads * 1024 * 1024);
+        flume::bounded(threads * 1024 * 1024);
 
     let (counter_job_tx, counter_job_rx): (Sender<CounterJob>, Receiver<CounterJob>) =
-        flume::bounded(opts.threads * 1024 * 1024);
+        flume::bounded(threads * 1024 * 1024);
 
-    let contig_to_offset = build_contig_to_offset(&opts.genome);
-    let counter_handle = std::thread::spawn(move || {
+    let contig_to_offset = build_contig_to_offset(genome);
+    let counter_handle = std::thread::spawn(move || -> Vec<(bed::Record<4>, LocusHistogram)> {
         info!("Computing counts by locus");
         let mut key_to_locus: HashMap<String, bed::Record<4>> = HashMap::new();
-        let mut counter: HashMap<String, HashMap<usize, HashMap<bool, usize>>> = HashMap::new();
+        let mut counter: HashMap<String, LocusHistogram> = HashMap::new();
         while let Ok(count_job) = counter_job_rx.recv() {
             // Add to the key -> locus map
             let locus_key = count_job.locus.to_string();
-            key_to_locus.entry(locus_key.clone()).or_insert(count_job.locus);
+            key_to_locus
+                .entry(locus_key.clone())
+                .or_insert(count_job.locus);
 
             // Get the map from repeat count to T/N count map
             let repeat = counter.entry(locus_key).or_insert_with(HashMap::new);
@@ -194,19 +626,10 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
         }
 
         info!("Collecting counts by locus");
-        let mut loci: Vec<(&String, &bed::Record<4>)> = counter
-            .keys()
-            .map(|key| {
-                let locus = match key_to_locus.get(key) {
-                    Some(l) => l,
-                    None => panic!("Bug: locus not found {}", key),
-                };
-                (key, locus)
-            })
-            .collect();
+        let mut loci: Vec<(String, bed::Record<4>)> = key_to_locus.into_iter().collect();
 
         info!("Sorting counts by locus");
-        loci.sort_by_cached_key(|(key, locus)| {
+        loci.sort_by_cached_key(|(_key, locus)| {
             let offset = match contig_to_offset.get(locus.reference_sequence_name()) {
                 Some(offset) => offset,
                 None => panic!(
@@ -214,51 +637,40 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
                     locus.reference_sequence_name()
                 ),
             };
-            (offset, locus.start_position(), locus.end_position())
+            (*offset, locus.start_position(), locus.end_position())
         });
 
-        info!("Outputting counts by locus");
-        for (key, locus) in loci {
-            let repeat = match counter.get(key) {
-                Some(r) => r,
-                None => panic!("Bug: locus not found {}", key),
-            };
-
-            let mut lengths: Vec<&usize> = repeat.keys().collect();
-            lengths.sort();
-
-            for length in lengths {
-                let repeat_count = match repeat.get(length) {
-                    Some(rc) => rc,
-                    None => panic!("Bug: Cannot find repeat length: {}", length),
+        loci.into_iter()
+            .map(|(key, locus)| {
+                let repeat = match counter.remove(&key) {
+                    Some(r) => r,
+                    None => panic!("Bug: locus not found {}", key),
                 };
-                let normal_count = repeat_count.get(&true).unwrap_or(&0);
-                let tumor_count = repeat_count.get(&false).unwrap_or(&0);
-                println!(
-                    "{}:{}-{}\t{}\t{}\t{}",
-                    locus.reference_sequence_name(),
-                    locus.start_position(),
-                    locus.end_position(),
-                    length,
-                    normal_count,
-                    tumor_count
-                );
-            }
-        }
+                (locus, repeat)
+            })
+            .collect()
     });
 
-    let worker_handles: Vec<std::thread::JoinHandle<AnyhowResult<()>>> = (0..opts.threads)
+    let qc_tally = std::sync::Arc::new(QcTally::default());
+    let qc = *qc;
+
+    let worker_handles: Vec<std::thread::JoinHandle<AnyhowResult<()>>> = (0..threads)
         .map(|_i| {
             let rx = worker_job_rx.clone();
             let tx = counter_job_tx.clone();
+            let tally = qc_tally.clone();
             std::thread::spawn(move || {
                 while let Ok(worker_job) = rx.recv() {
-                    let count_job = CounterJob {
-                        locus: worker_job.locus,
-                        count: 0,
-                        is_normal: worker_job.is_normal,
-                    };
-                    tx.send(count_job).unwrap();
+                    if let Some(count) =
+                        qc_and_count(&qc, &worker_job.locus, &worker_job.record, &tally)
+                    {
+                        let count_job = CounterJob {
+                            locus: worker_job.locus,
+                            count,
+                            is_normal: worker_job.is_normal,
+                        };
+                        tx.send(count_job).unwrap();
+                    }
                 }
                 drop(tx);
                 Ok(())
@@ -267,38 +679,50 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
         // Collect is needed to force the evaluation of the closure and start the loops
         .collect();
 
-    let bam_query_handles: Vec<std::thread::JoinHandle<AnyhowResult<()>>> = (0..opts.threads)
+    let io = Io::default();
+    let normal_bai_and_header = BaiAndHeader::new(&io, normal, true);
+    let tumor_bai_and_header = BaiAndHeader::new(&io, tumor, false);
+
+    // Each query worker opens the normal and tumor BAM once and reuses the same readers across
+    // every job it receives, rather than re-opening (and re-seeking) the BAM per locus.
+    let bam_query_handles: Vec<std::thread::JoinHandle<AnyhowResult<()>>> = (0..threads)
         .map(|_i| {
             let rx = bam_query_job_rx.clone();
             let tx = worker_job_tx.clone();
+            let normal = normal.clone();
+            let tumor = tumor.clone();
+            let normal_bai_and_header = normal_bai_and_header.clone();
+            let tumor_bai_and_header = tumor_bai_and_header.clone();
             std::thread::spawn(move || {
+                let mut normal_reader = File::open(&normal)
+                    .map(bam::Reader::new)
+                    .with_context(|| format!("Could not open normal BAM for reading: {:?}", normal))
+                    .unwrap();
+                let mut tumor_reader = File::open(&tumor)
+                    .map(bam::Reader::new)
+                    .with_context(|| format!("Could not open tumor BAM for reading: {:?}", tumor))
+                    .unwrap();
+
                 while let Ok(bam_query_job) = rx.recv() {
-                    let name = if bam_query_job.is_normal { "normal" } else { "tumor" };
-                    let locus = bam_query_job.locus;
-                    let region = noodles_core::Region::new(
-                        locus.reference_sequence_name(),
-                        locus.start_position()..=locus.end_position(),
-                    );
-
-                    let mut reader = File::open(&bam_query_job.bam)
-                        .map(bam::Reader::new)
-                        .with_context(|| {
-                            format!(
-                                "Could not open {} BAM for reading: {:?}",
-                                name, bam_query_job.bam
-                            )
-                        })
-                        .unwrap();
-
-                    query_reads(
-                        &bam_query_job.header,
-                        &mut reader,
-                        &bam_query_job.bai,
-                        bam_query_job.is_normal,
-                        &locus,
-                        &region,
-                        &tx,
-                    );
+                    if bam_query_job.is_normal {
+                        query_reads_batch(
+                            &normal_bai_and_header.header,
+                            &mut normal_reader,
+                            &normal_bai_and_header.bai,
+                            true,
+                            &bam_query_job.loci,
+                            &tx,
+                        );
+                    } else {
+                        query_reads_batch(
+                            &tumor_bai_and_header.header,
+                            &mut tumor_reader,
+                            &tumor_bai_and_header.bai,
+                            false,
+                            &bam_query_job.loci,
+                            &tx,
+                        );
+                    }
                 }
                 drop(tx);
                 Ok(())
@@ -307,17 +731,20 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
         // Collect is needed to force the evaluation of the closure and start the loops
         .collect();
 
-    // Read in the BED, and send jobs to query the BAM
-    let io = Io::default();
+    // Read in the BED, batching adjacent loci, and send jobs to query the BAM
     let mut bed_reader = io
-        .new_reader(&opts.bedfile)
+        .new_reader(bedfile)
         .map(bed::Reader::new)
-        .with_context(|| format!("Could not open BED for reading: {:?}", opts.bedfile))
+        .with_context(|| format!("Could not open BED for reading: {:?}", bedfile))
         .unwrap();
-    let normal_bai_and_header = BaiAndHeader::new(&io, &opts.normal, true);
-    let tumor_bai_and_header = BaiAndHeader::new(&io, &opts.tumor, false);
 
-    let contig_to_offset = build_contig_to_offset(&opts.genome);
+    let contig_to_offset = build_contig_to_offset(genome);
+    let mut batch: Vec<bed::Record<4>> = Vec::new();
+    let mut batch_contig: Option<String> = None;
+    let mut batch_end: Option<usize> = None;
+    let mut num_loci = 0usize;
+    let mut num_loci_without_motif = 0usize;
+
     for (index, bed_result) in bed_reader.records::<4>().enumerate() {
         let locus = bed_result
             .with_context(|| format!("Could not parse the {}th BED record", index + 1))
@@ -329,6 +756,11 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
             locus.reference_sequence_name()
         );
 
+        num_loci += 1;
+        if motif_length(&locus).is_none() {
+            num_loci_without_motif += 1;
+        }
+
         if (index + 1) % 1000 == 0 {
             info!(
                 "Processed {} loci; last: {}:{}-{}",
@@ -339,45 +771,529 @@ pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
             );
         }
 
-        let normal_job = BamQueryJob {
-            locus: locus.clone(),
-            bam: opts.normal.clone(),
-            bai: normal_bai_and_header.bai.clone(),
-            header: normal_bai_and_header.header.clone(),
-            is_normal: true,
-        };
-        let tumor_job = BamQueryJob {
-            locus: locus.clone(),
-            bam: opts.tumor.clone(),
-            bai: tumor_bai_and_header.bai.clone(),
-            header: tumor_bai_and_header.header.clone(),
-            is_normal: false,
-        };
+        let locus_contig = locus.reference_sequence_name().to_string();
+        let locus_start = usize::from(locus.start_position());
+        let extends_batch = extends_batch(
+            batch.len(),
+            batch_contig.as_deref(),
+            batch_end,
+            &locus_contig,
+            locus_start,
+        );
 
-        bam_query_job_tx.send(normal_job)?;
-        bam_query_job_tx.send(tumor_job)?;
+        if !extends_batch {
+            flush_batch(&mut batch, &bam_query_job_tx)?;
+            batch_contig = Some(locus_contig);
+        }
+        batch_end = Some(usize::from(locus.end_position()));
+        batch.push(locus);
     }
+    flush_batch(&mut batch, &bam_query_job_tx)?;
     drop(bam_query_job_tx);
 
     // Close the worker handles
-    bam_query_handles.into_iter().try_for_each(|handle| match handle.join() {
-        Ok(result) => result,
-        Err(e) => std::panic::resume_unwind(e),
-    })?;
+    bam_query_handles
+        .into_iter()
+        .try_for_each(|handle| match handle.join() {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e),
+        })?;
     drop(worker_job_tx);
 
     // Close the worker handles
-    worker_handles.into_iter().try_for_each(|handle| match handle.join() {
-        Ok(result) => result,
-        Err(e) => std::panic::resume_unwind(e),
-    })?;
+    worker_handles
+        .into_iter()
+        .try_for_each(|handle| match handle.join() {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e),
+        })?;
     drop(counter_job_tx);
+    qc_tally.log();
 
     // Close the counter handle
-    match counter_handle.join() {
+    let counts = match counter_handle.join() {
         Ok(result) => result,
         Err(e) => std::panic::resume_unwind(e),
     };
 
+    // Equalize normal/tumor coverage per locus before the coverage filter below sees it, so a
+    // locus isn't dropped (or kept) based on depth that subsampling would have removed anyway.
+    let counts: Vec<(bed::Record<4>, LocusHistogram)> = if downsample.enabled {
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(index, (locus, histogram))| {
+                let mut rng = StdRng::seed_from_u64(downsample.seed ^ index as u64);
+                (
+                    locus,
+                    downsample_locus(histogram, downsample.target, &mut rng),
+                )
+            })
+            .collect()
+    } else {
+        counts
+    };
+
+    // Drop loci whose surviving normal or tumor coverage falls below the minimum after QC
+    // filtering.
+    let counts = counts
+        .into_iter()
+        .filter(|(_locus, histogram)| {
+            let mut normal_coverage = 0usize;
+            let mut tumor_coverage = 0usize;
+            for by_sample in histogram.values() {
+                normal_coverage += by_sample.get(&true).unwrap_or(&0);
+                tumor_coverage += by_sample.get(&false).unwrap_or(&0);
+            }
+            normal_coverage >= qc.min_locus_coverage && tumor_coverage >= qc.min_locus_coverage
+        })
+        .collect();
+
+    // A BED record's name only encodes a repeat unit in the legacy `(UNIT)COUNT` format (see
+    // `motif_length`); `repeat-finder --output-format bed6`/`tsv` doesn't write that format, so
+    // feeding either back in here silently drops every read at every locus with no counts and no
+    // error. Fail loudly instead, since that's indistinguishable from a healthy empty run otherwise.
+    if num_loci > 0 && num_loci_without_motif == num_loci {
+        bail!(
+            "None of the {num_loci} loci in {bedfile:?} have a name in the `(UNIT)COUNT` format \
+             `motif_length` expects, so no reads would be counted at any locus. Was this BED file \
+             produced by `repeat-finder --output-format bed6` or `tsv`? Only `bed4` output can be \
+             used as the `--bedfile` for repeat-counter/detect."
+        );
+    }
+
+    Ok(counts)
+}
+
+// Run extract
+#[allow(clippy::too_many_lines)]
+pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
+    let qc = QcFilters {
+        min_read_mean_base_quality: opts.min_read_mean_base_quality as f64,
+        min_locus_mean_base_quality: opts.min_locus_mean_base_quality as f64,
+        min_read_length: opts.min_read_length as usize,
+        min_locus_coverage: opts.min_locus_coverage as usize,
+    };
+    let downsample = DownsampleOpts {
+        enabled: opts.downsample,
+        target: opts.downsample_target.map(|target| target as usize),
+        seed: opts.seed,
+    };
+    let counts = collect_counts(
+        &opts.normal,
+        &opts.tumor,
+        &opts.bedfile,
+        &opts.genome,
+        opts.threads,
+        &qc,
+        &downsample,
+    )?;
+
+    info!("Outputting counts by locus");
+    for (locus, repeat) in counts {
+        let mut lengths: Vec<&usize> = repeat.keys().collect();
+        lengths.sort();
+
+        for length in lengths {
+            let repeat_count = match repeat.get(length) {
+                Some(rc) => rc,
+                None => panic!("Bug: Cannot find repeat length: {}", length),
+            };
+            let normal_count = repeat_count.get(&true).unwrap_or(&0);
+            let tumor_count = repeat_count.get(&false).unwrap_or(&0);
+            println!(
+                "{}:{}-{}\t{}\t{}\t{}",
+                locus.reference_sequence_name(),
+                locus.start_position(),
+                locus.end_position(),
+                length,
+                normal_count,
+                tumor_count
+            );
+        }
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::compute_locus_stats;
+    use super::downsample_locus;
+    use super::extends_batch;
+    use super::flush_batch;
+    use super::qc_decision;
+    use super::subsample_counts;
+    use super::BamQueryJob;
+    use super::Kind;
+    use super::LocusHistogram;
+    use super::LocusReadStats;
+    use super::QcFilters;
+    use super::QcTally;
+    use noodles::bed;
+    use noodles::core::Position;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn locus(contig: &str, start: usize, end: usize) -> bed::Record<4> {
+        bed::Record::<4>::builder()
+            .set_reference_sequence_name(contig)
+            .set_start_position(Position::try_from(start).unwrap())
+            .set_end_position(Position::try_from(end).unwrap())
+            .set_name(bed::record::Name::from_str("(CAG)5").unwrap())
+            .build()
+            .unwrap()
+    }
+
+    // A tract spanning ref positions 100..=108 (inclusive), three bases per repeat unit.
+    const TRACT_START: usize = 100;
+    const TRACT_END: usize = 108;
+    const MOTIF_LEN: usize = 3;
+
+    #[test]
+    fn test_simple_full_match_counts_repeats() {
+        let quality_scores = [30u8; 9];
+        let stats = compute_locus_stats(
+            TRACT_START,
+            TRACT_END,
+            Some(MOTIF_LEN),
+            TRACT_START,
+            &quality_scores,
+            [(Kind::Match, 9)].into_iter(),
+        );
+        assert_eq!(stats.repeat_count, Some(3));
+        assert_eq!(stats.aligned_length, 9);
+        assert!((stats.mean_read_quality - 30.0).abs() < f64::EPSILON);
+        assert_eq!(stats.mean_locus_quality, Some(30.0));
+    }
+
+    #[test]
+    fn test_insertion_inside_tract_extends_repeat_count() {
+        // 4 matched bases, a 3bp insertion strictly inside the tract, then 5 more matched bases:
+        // the insertion adds to the tract length exactly like a matched base would.
+        let quality_scores = [30u8; 12];
+        let stats = compute_locus_stats(
+            TRACT_START,
+            TRACT_END,
+            Some(MOTIF_LEN),
+            TRACT_START,
+            &quality_scores,
+            [(Kind::Match, 4), (Kind::Insertion, 3), (Kind::Match, 5)].into_iter(),
+        );
+        // tract_len = 4 (match) + 3 (insertion) + 5 (match, but tract only extends to 108, so the
+        // whole 5bp match overlaps) = 12 bases -> 4 repeat units of 3bp each.
+        assert_eq!(stats.repeat_count, Some(4));
+        assert_eq!(stats.aligned_length, 12);
+    }
+
+    #[test]
+    fn test_deletion_inside_tract_shrinks_repeat_count() {
+        // 4 matched bases, a 3bp deletion inside the tract, then 5 more matched bases: the
+        // deletion removes from the tract length instead of adding to it.
+        let quality_scores = [30u8; 9];
+        let stats = compute_locus_stats(
+            TRACT_START,
+            TRACT_END,
+            Some(MOTIF_LEN),
+            TRACT_START,
+            &quality_scores,
+            [(Kind::Match, 4), (Kind::Deletion, 3), (Kind::Match, 5)].into_iter(),
+        );
+        // tract_len = 4 (match) - 3 (deletion) + 2 (match, only 107..=108 still falls in the
+        // tract) = 3 bases -> 1 repeat unit.
+        assert_eq!(stats.repeat_count, Some(1));
+        assert_eq!(stats.aligned_length, 9);
+    }
+
+    #[test]
+    fn test_soft_clip_on_tract_boundary_marks_not_fully_observed() {
+        // The read is soft-clipped exactly up to the tract's start position, so even though the
+        // remaining bases fully cover the tract, the count can't be trusted.
+        let quality_scores = [30u8; 14];
+        let stats = compute_locus_stats(
+            TRACT_START,
+            TRACT_END,
+            Some(MOTIF_LEN),
+            TRACT_START,
+            &quality_scores,
+            [(Kind::SoftClip, 5), (Kind::Match, 9)].into_iter(),
+        );
+        assert_eq!(stats.repeat_count, None);
+        // The locus quality and aligned length are still reported even when the repeat count
+        // itself is suppressed.
+        assert_eq!(stats.mean_locus_quality, Some(30.0));
+        assert_eq!(stats.aligned_length, 9);
+    }
+
+    #[test]
+    fn test_no_motif_length_yields_no_repeat_count() {
+        let quality_scores = [30u8; 9];
+        let stats = compute_locus_stats(
+            TRACT_START,
+            TRACT_END,
+            None,
+            TRACT_START,
+            &quality_scores,
+            [(Kind::Match, 9)].into_iter(),
+        );
+        assert_eq!(stats.repeat_count, None);
+    }
+
+    fn qc_filters() -> QcFilters {
+        QcFilters {
+            min_read_mean_base_quality: 25.0,
+            min_locus_mean_base_quality: 30.0,
+            min_read_length: 35,
+            min_locus_coverage: 30,
+        }
+    }
+
+    fn passing_stats() -> LocusReadStats {
+        LocusReadStats {
+            repeat_count: Some(10),
+            mean_read_quality: 30.0,
+            aligned_length: 50,
+            mean_locus_quality: Some(35.0),
+        }
+    }
+
+    #[test]
+    fn test_qc_decision_passes_good_read() {
+        let tally = QcTally::default();
+        let count = qc_decision(&qc_filters(), &passing_stats(), &tally);
+        assert_eq!(count, Some(10));
+        assert_eq!(
+            tally
+                .read_mean_quality
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    fn test_qc_decision_rejects_low_read_quality() {
+        let tally = QcTally::default();
+        let stats = LocusReadStats {
+            mean_read_quality: 10.0,
+            ..passing_stats()
+        };
+        let count = qc_decision(&qc_filters(), &stats, &tally);
+        assert_eq!(count, None);
+        assert_eq!(
+            tally
+                .read_mean_quality
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_qc_decision_rejects_short_read() {
+        let tally = QcTally::default();
+        let stats = LocusReadStats {
+            aligned_length: 10,
+            ..passing_stats()
+        };
+        let count = qc_decision(&qc_filters(), &stats, &tally);
+        assert_eq!(count, None);
+        assert_eq!(
+            tally.read_length.load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_qc_decision_rejects_low_locus_quality() {
+        let tally = QcTally::default();
+        let stats = LocusReadStats {
+            mean_locus_quality: Some(5.0),
+            ..passing_stats()
+        };
+        let count = qc_decision(&qc_filters(), &stats, &tally);
+        assert_eq!(count, None);
+        assert_eq!(
+            tally
+                .locus_mean_quality
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_qc_decision_rejects_tract_not_observed() {
+        let tally = QcTally::default();
+        // `mean_locus_quality: None` happens when no aligned base overlaps the locus (e.g. the
+        // whole tract is deleted in the read).
+        let stats = LocusReadStats {
+            mean_locus_quality: None,
+            ..passing_stats()
+        };
+        let count = qc_decision(&qc_filters(), &stats, &tally);
+        assert_eq!(count, None);
+        assert_eq!(
+            tally
+                .tract_not_observed
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_qc_decision_rejects_missing_repeat_count_despite_good_quality() {
+        let tally = QcTally::default();
+        let stats = LocusReadStats {
+            repeat_count: None,
+            ..passing_stats()
+        };
+        let count = qc_decision(&qc_filters(), &stats, &tally);
+        assert_eq!(count, None);
+        assert_eq!(
+            tally
+                .tract_not_observed
+                .load(std::sync::atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_extends_batch_empty_batch_never_extends() {
+        assert!(!extends_batch(0, None, None, "chr1", 100));
+    }
+
+    #[test]
+    fn test_extends_batch_same_contig_within_window_extends() {
+        assert!(extends_batch(
+            1,
+            Some("chr1"),
+            Some(100),
+            "chr1",
+            100 + super::BATCH_WINDOW
+        ));
+    }
+
+    #[test]
+    fn test_extends_batch_same_contig_beyond_window_does_not_extend() {
+        assert!(!extends_batch(
+            1,
+            Some("chr1"),
+            Some(100),
+            "chr1",
+            101 + super::BATCH_WINDOW
+        ));
+    }
+
+    #[test]
+    fn test_extends_batch_different_contig_does_not_extend() {
+        assert!(!extends_batch(1, Some("chr1"), Some(100), "chr2", 100));
+    }
+
+    #[test]
+    fn test_extends_batch_at_capacity_does_not_extend() {
+        assert!(!extends_batch(
+            super::MAX_BATCH_LOCI,
+            Some("chr1"),
+            Some(100),
+            "chr1",
+            100
+        ));
+    }
+
+    #[test]
+    fn test_flush_batch_sends_one_job_per_sample_and_clears_batch() {
+        let (tx, rx) = flume::unbounded::<BamQueryJob>();
+        let mut batch = vec![locus("chr1", 100, 108), locus("chr1", 200, 208)];
+
+        flush_batch(&mut batch, &tx).unwrap();
+
+        assert!(batch.is_empty());
+        let first = rx.try_recv().unwrap();
+        let second = rx.try_recv().unwrap();
+        assert!(first.is_normal);
+        assert!(!second.is_normal);
+        assert_eq!(first.loci.len(), 2);
+        assert_eq!(second.loci.len(), 2);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_flush_batch_empty_batch_sends_nothing() {
+        let (tx, rx) = flume::unbounded::<BamQueryJob>();
+        let mut batch: Vec<bed::Record<4>> = Vec::new();
+
+        flush_batch(&mut batch, &tx).unwrap();
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subsample_counts_is_noop_under_target() {
+        let counts = HashMap::from([(10, 5), (11, 5)]);
+        let mut rng = StdRng::seed_from_u64(42);
+        let subsampled = subsample_counts(&counts, 20, &mut rng);
+        assert_eq!(subsampled, counts);
+    }
+
+    #[test]
+    fn test_subsample_counts_truncates_to_target_total() {
+        let counts = HashMap::from([(10, 50), (11, 50)]);
+        let mut rng = StdRng::seed_from_u64(42);
+        let subsampled = subsample_counts(&counts, 20, &mut rng);
+        assert_eq!(subsampled.values().sum::<usize>(), 20);
+    }
+
+    #[test]
+    fn test_subsample_counts_is_reproducible_under_the_same_seed() {
+        let counts = HashMap::from([(10, 50), (11, 50), (12, 50)]);
+        let mut first_rng = StdRng::seed_from_u64(7);
+        let mut second_rng = StdRng::seed_from_u64(7);
+        let first = subsample_counts(&counts, 30, &mut first_rng);
+        let second = subsample_counts(&counts, 30, &mut second_rng);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_downsample_locus_equalizes_to_the_lesser_coverage() {
+        let histogram: LocusHistogram = HashMap::from([
+            (10, HashMap::from([(true, 40), (false, 10)])),
+            (11, HashMap::from([(true, 40), (false, 10)])),
+        ]);
+        let mut rng = StdRng::seed_from_u64(42);
+        let downsampled = downsample_locus(histogram, None, &mut rng);
+
+        let normal_total: usize = downsampled
+            .values()
+            .filter_map(|by_sample| by_sample.get(&true))
+            .sum();
+        let tumor_total: usize = downsampled
+            .values()
+            .filter_map(|by_sample| by_sample.get(&false))
+            .sum();
+        // Tumor coverage (20) was already the lesser of the two, so normal (80) is subsampled
+        // down to match it.
+        assert_eq!(normal_total, 20);
+        assert_eq!(tumor_total, 20);
+    }
+
+    #[test]
+    fn test_downsample_locus_respects_a_fixed_target() {
+        let histogram: LocusHistogram = HashMap::from([
+            (10, HashMap::from([(true, 40), (false, 40)])),
+            (11, HashMap::from([(true, 40), (false, 40)])),
+        ]);
+        let mut rng = StdRng::seed_from_u64(42);
+        let downsampled = downsample_locus(histogram, Some(15), &mut rng);
+
+        let normal_total: usize = downsampled
+            .values()
+            .filter_map(|by_sample| by_sample.get(&true))
+            .sum();
+        let tumor_total: usize = downsampled
+            .values()
+            .filter_map(|by_sample| by_sample.get(&false))
+            .sum();
+        assert_eq!(normal_total, 15);
+        assert_eq!(tumor_total, 15);
+    }
+}
@@ -1,3 +1,10 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::thread::JoinHandle;
 use std::{path::PathBuf, str::FromStr};
 
 use anyhow::Context;
@@ -5,15 +12,18 @@ use anyhow::Result;
 use clap::Parser;
 use env_logger::Env;
 use fgoxide::io::Io;
+use flate2::bufread::MultiGzDecoder;
+use flume::{Receiver, Sender};
+use xz2::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
 use crate::utils::built_info;
 use noodles::bed;
-use noodles::fasta;
 
 use noodles::core::Position;
 
 /// Find repeats in your reference FASTA
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(name = "repeat-finder", verbatim_doc_comment, version = built_info::VERSION.as_str())]
 pub struct Opts {
     /// The input reference genome FASTA
@@ -43,99 +53,426 @@ pub struct Opts {
     /// The maximum k-mer length
     #[clap(short = 'L', default_value = "5", long, display_order = 7)]
     pub max_repeat_length: usize,
+
+    /// Keep the legacy permissive behavior of upper-casing every byte with a bitwise mask and
+    /// treating it as an ordinary base, instead of terminating repeat tracking at `N`s and IUPAC
+    /// ambiguity codes.
+    #[clap(long, display_order = 8)]
+    pub permissive_bases: bool,
+
+    /// Maximum fraction of mismatching bases (relative to the repeat's span) tolerated before an
+    /// imperfect microsatellite is terminated. The default of 0.0 only calls perfectly pure
+    /// tandem repeats.
+    #[clap(long, default_value = "0.0", display_order = 9)]
+    pub max_impurity: f64,
+
+    /// Maximum run of consecutive mismatching bases tolerated before an imperfect microsatellite
+    /// is terminated. The default of 0 only calls perfectly pure tandem repeats.
+    #[clap(long, default_value = "0", display_order = 10)]
+    pub max_gap: usize,
+
+    /// The output column layout: `bed4` packs the unit and copy number into the name field
+    /// (legacy, lossy); `bed6` reports the unit alone in the name field and the purity as the BED
+    /// score; `tsv` reports the unit, copy number, span, and purity in dedicated columns.
+    ///
+    /// Only `bed4` output can be fed back in as the `--bedfile` for `repeat-counter`/`detect`:
+    /// those subcommands' `motif_length` only understands the legacy `(UNIT)COUNT` name, and their
+    /// BED reader is hardcoded to 4 columns, so a `bed6` name (unit only, no count) fails to parse
+    /// and `tsv` isn't a BED file at all. Given `bed6`/`tsv` output, those subcommands stop with an
+    /// error instead of producing counts.
+    #[clap(long, value_enum, default_value = "bed4", display_order = 11)]
+    pub output_format: OutputFormat,
+
+    /// The number of threads to use. Each contig is scanned independently by a worker thread, so
+    /// this has no effect on a single-contig reference.
+    #[clap(long, default_value = "1", display_order = 12)]
+    pub threads: usize,
 }
 
-// Run extract
-#[allow(clippy::too_many_lines)]
-pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
-    // Build the finders
-    let mut finders: Vec<KmerFinder> =
-        Vec::with_capacity(opts.max_repeat_length - opts.min_repeat_length + 1);
-    let mut i = opts.min_repeat_length;
-    while i <= opts.max_repeat_length {
-        finders.push(KmerFinder::new(i));
-        i += 1;
-    }
+/// The output column layout for called microsatellites. See [`Opts::output_format`].
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Bed4,
+    Bed6,
+    Tsv,
+}
 
-    // Open the input and output
-    let io = Io::default();
+/// A contig's full sequence, read eagerly by the dispatcher so it can be handed to a worker
+/// thread. Parallelizing across contigs means a contig's bases are buffered in full (unlike the
+/// single-threaded path's one-line-at-a-time streaming), but this is bounded by the job channel's
+/// capacity rather than the whole genome. Only used when `--threads` is greater than 1; the default
+/// single-threaded path never buffers a contig (see [`run_streaming`]).
+struct ContigJob {
+    index: usize,
+    name: String,
+    sequence: Vec<u8>,
+}
 
-    let mut reader = io
-        .new_reader(&opts.input)
-        .map(fasta::Reader::new)
-        .with_context(|| format!("Could not open FASTA for reading: {:?}", opts.input))?;
-    let mut writer = io
-        .new_writer(&opts.output)
-        .map(bed::Writer::new)
-        .with_context(|| format!("Could not open BED for writing: {:?}", opts.output))?;
-
-    // Go through each contig, one at a time
-    for (index, result) in reader.records().enumerate() {
-        let record =
-            result.with_context(|| format!("Could not parse the {}th FASTA record", index + 1))?;
-        let contig = record.name();
-
-        for finder in &mut finders {
-            finder.reset();
+/// The repeats found in one [`ContigJob`], tagged with its dispatch order so the writer can emit
+/// records in input order even though contigs finish scanning out of order.
+struct ContigResult {
+    index: usize,
+    records: Vec<ScoredRepeat>,
+}
+
+/// The mutable state needed to scan a contig for microsatellites: one [`KmerFinder`] per k-mer
+/// length in `[min_repeat_length, max_repeat_length]`, plus the running 1-based position of the
+/// most recently added base (0 before any base). Factored out of a per-contig loop so the same base-by-base step can be driven either
+/// by an in-memory slice (the multi-threaded path's [`scan_contig`]) or directly by
+/// [`SequenceSource::for_each_base`] (the single-threaded path's [`run_streaming`]), without ever
+/// requiring a whole contig's sequence to be buffered.
+struct ContigScanner {
+    finders: Vec<KmerFinder>,
+    position: usize,
+}
+
+impl ContigScanner {
+    fn new(opts: &Opts) -> ContigScanner {
+        let mut finders: Vec<KmerFinder> =
+            Vec::with_capacity(opts.max_repeat_length - opts.min_repeat_length + 1);
+        let mut i = opts.min_repeat_length;
+        while i <= opts.max_repeat_length {
+            finders.push(KmerFinder::new(i, opts.max_gap, opts.max_impurity));
+            i += 1;
+        }
+        ContigScanner {
+            finders,
+            position: 0,
         }
+    }
+
+    /// Feeds one base, calling `on_repeat` with the smallest repeat found ending at this position,
+    /// if any.
+    fn add_base(
+        &mut self,
+        opts: &Opts,
+        contig: &str,
+        raw_base: u8,
+        mut on_repeat: impl FnMut(ScoredRepeat),
+    ) {
+        self.position += 1;
 
-        // Go through each base, one at a time
-        let mut i = 1;
-        while i <= record.sequence().len() {
-            let position = Position::try_from(i)
-                .with_context(|| format!("Could not create a Position from {}", i))?;
-            let base: u8 = record
-                .sequence()
-                .get(position)
-                .with_context(|| format!("Could not retrieve base at {}:{}", record.name(), i))
-                .unwrap()
-                & 0xdf; // to upper case
-
-            // Output the smallest repeat found ending at this position.
+        let base = if opts.permissive_bases {
+            Some(raw_base & 0xdf)
+        } else {
+            normalize_base(raw_base)
+        };
+
+        let Some(base) = base else {
+            // `N`/IUPAC ambiguity code: emit (and reset) every finder so no repeat is reported as
+            // spanning the ambiguous base.
             let mut found = false;
-            for finder in &mut finders {
-                if let Some(repeat) = finder.add_maybe_emit(base, !found) {
-                    if found {
-                        continue;
-                    } else if let Some(rec) = to_bed_record(opts, contig, i, finder, &repeat) {
-                        writer
-                            .write_record(&rec)
-                            .with_context(|| format!("Could not write BED record {:?}", rec))
-                            .unwrap();
-                        found = true;
+            for finder in &mut self.finders {
+                if let Some(repeat) = finder.emit() {
+                    if !found {
+                        if let Some(scored) =
+                            to_scored_repeat(opts, contig, self.position, finder, &repeat)
+                        {
+                            on_repeat(scored);
+                            found = true;
+                        }
                     }
                 }
+                finder.reset();
+            }
+            return;
+        };
+
+        // Output the smallest repeat found ending at this position.
+        let mut found = false;
+        for finder in &mut self.finders {
+            if let Some(repeat) = finder.add_maybe_emit(base, !found) {
+                if found {
+                    continue;
+                } else if let Some(scored) =
+                    to_scored_repeat(opts, contig, self.position, finder, &repeat)
+                {
+                    on_repeat(scored);
+                    found = true;
+                }
             }
-            i += 1;
         }
+    }
 
-        // Emit any repeat that goes to the end of the contig
-        for finder in &mut finders {
+    /// Flushes any repeat that runs to the end of the contig.
+    fn finish(&mut self, opts: &Opts, contig: &str, mut on_repeat: impl FnMut(ScoredRepeat)) {
+        for finder in &mut self.finders {
             if let Some(repeat) = finder.emit() {
                 // only output the first repeat found at this position
-                if let Some(rec) = to_bed_record(opts, contig, i, finder, &repeat) {
-                    writer
-                        .write_record(&rec)
-                        .with_context(|| format!("Could not write BED record {:?}", rec))
-                        .unwrap();
+                if let Some(scored) =
+                    to_scored_repeat(opts, contig, self.position + 1, finder, &repeat)
+                {
+                    on_repeat(scored);
                     break;
                 }
             }
         }
     }
+}
+
+/// Scans a single contig's already-buffered sequence for microsatellites. Used by the
+/// multi-threaded path, where a worker thread needs an owned, `Send`-able sequence; the
+/// single-threaded (default) path streams bases directly from [`SequenceSource`] through the same
+/// [`ContigScanner`] instead, via [`run_streaming`], and never buffers a whole contig.
+fn scan_contig(opts: &Opts, contig: &str, sequence: &[u8]) -> Vec<ScoredRepeat> {
+    let mut scanner = ContigScanner::new(opts);
+    let mut records = Vec::new();
+    for &raw_base in sequence {
+        scanner.add_base(opts, contig, raw_base, |scored| records.push(scored));
+    }
+    scanner.finish(opts, contig, |scored| records.push(scored));
+    records
+}
 
+// Run extract
+pub fn run(opts: &Opts) -> Result<(), anyhow::Error> {
+    let io = Io::default();
+    let mut writer = RepeatWriter::open(opts, &io)?;
+
+    if opts.threads <= 1 {
+        run_streaming(opts, &mut writer)
+    } else {
+        run_parallel(opts, &mut writer)
+    }
+}
+
+/// Scans every contig in `opts.input` sequentially on the calling thread, streaming bases directly
+/// from [`SequenceSource`] through a [`ContigScanner`] one line at a time. A contig's sequence
+/// (e.g. a ~250MB human chromosome) is never buffered in full. This is the default (`--threads 1`)
+/// path.
+fn run_streaming(opts: &Opts, writer: &mut RepeatWriter) -> Result<(), anyhow::Error> {
+    let mut source = SequenceSource::open(&opts.input)?;
+    while let Some(name) = source.next_contig()? {
+        let mut scanner = ContigScanner::new(opts);
+        source.for_each_base(|base| {
+            scanner.add_base(opts, &name, base, |scored| writer.write(&scored));
+        })?;
+        scanner.finish(opts, &name, |scored| writer.write(&scored));
+    }
     Ok(())
 }
 
-/// Converts the given repeat to a [`bed::Record`] if the repeat passes all the filters, otherwise
+/// Scans every contig in `opts.input` across `opts.threads` worker threads, one contig per job.
+/// Each job buffers its contig's full sequence so it can be handed to a worker thread (see
+/// [`ContigJob`]); this is the tradeoff for parallelizing across contigs rather than within one, and
+/// is bounded by the job channel's capacity (`opts.threads * 4` queued contigs, plus one per worker
+/// and one being read), not the whole genome.
+#[allow(clippy::too_many_lines)]
+fn run_parallel(opts: &Opts, writer: &mut RepeatWriter) -> Result<(), anyhow::Error> {
+    let (contig_job_tx, contig_job_rx): (Sender<ContigJob>, Receiver<ContigJob>) =
+        flume::bounded(opts.threads * 4);
+    let (contig_result_tx, contig_result_rx): (Sender<ContigResult>, Receiver<ContigResult>) =
+        flume::bounded(opts.threads * 4);
+
+    let worker_handles: Vec<JoinHandle<()>> = (0..opts.threads)
+        .map(|_i| {
+            let rx = contig_job_rx.clone();
+            let tx = contig_result_tx.clone();
+            let opts = opts.clone();
+            std::thread::spawn(move || {
+                while let Ok(job) = rx.recv() {
+                    let records = scan_contig(&opts, &job.name, &job.sequence);
+                    tx.send(ContigResult {
+                        index: job.index,
+                        records,
+                    })
+                    .with_context(|| "Could not send ContigResult")
+                    .unwrap();
+                }
+            })
+        })
+        // Collect is needed to force the evaluation of the closure and start the loops
+        .collect();
+    drop(contig_result_tx);
+
+    // `SequenceSource` is itself a sequential stream, so contigs are still read one at a time;
+    // running that on its own thread lets it stay ahead of the workers instead of the two being
+    // serialized. `SequenceSource` transparently decompresses the input.
+    let input = opts.input.clone();
+    let dispatch_handle: JoinHandle<Result<()>> = std::thread::spawn(move || {
+        let mut source = SequenceSource::open(&input)?;
+        let mut index = 0usize;
+        while let Some(name) = source.next_contig()? {
+            let mut sequence = Vec::new();
+            source.for_each_base(|base| sequence.push(base))?;
+            contig_job_tx
+                .send(ContigJob {
+                    index,
+                    name,
+                    sequence,
+                })
+                .with_context(|| "Could not send ContigJob")?;
+            index += 1;
+        }
+        Ok(())
+    });
+
+    // Drain results as they arrive, but only write the records for a contig once every
+    // lower-indexed contig has already been written, so the output stays in genome order even
+    // though contigs finish scanning out of order.
+    let mut pending: HashMap<usize, Vec<ScoredRepeat>> = HashMap::new();
+    let mut next_index = 0usize;
+    while let Ok(result) = contig_result_rx.recv() {
+        pending.insert(result.index, result.records);
+        while let Some(records) = pending.remove(&next_index) {
+            for scored in &records {
+                writer.write(scored);
+            }
+            next_index += 1;
+        }
+    }
+
+    for handle in worker_handles {
+        handle
+            .join()
+            .with_context(|| "Worker thread panicked")
+            .unwrap();
+    }
+    dispatch_handle
+        .join()
+        .with_context(|| "Dispatch thread panicked")
+        .unwrap()?;
+
+    Ok(())
+}
+
+/// Magic byte prefixes used to sniff the compression format of an input stream before parsing it,
+/// mirroring the auto-detection needletail performs ahead of its FASTA parser.
+mod magic_bytes {
+    pub(super) const GZIP: [u8; 2] = [0x1f, 0x8b];
+    pub(super) const XZ: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+    pub(super) const ZSTD: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+}
+
+/// Opens `path` and, by sniffing its leading bytes, transparently wraps it in a gzip (this also
+/// covers bgzip, which is valid gzip), xz, or zstd decoder as needed. Plain-text input is returned
+/// unwrapped.
+fn open_decompressed(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("Could not open FASTA: {:?}", path))?;
+    let mut reader = BufReader::new(file);
+    let header = reader
+        .fill_buf()
+        .with_context(|| format!("Could not read FASTA: {:?}", path))?;
+
+    let decompressed: Box<dyn BufRead> = if header.starts_with(&magic_bytes::GZIP) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else if header.starts_with(&magic_bytes::XZ) {
+        Box::new(BufReader::new(XzDecoder::new(reader)))
+    } else if header.starts_with(&magic_bytes::ZSTD) {
+        Box::new(
+            ZstdDecoder::new(reader)
+                .with_context(|| format!("Could not open zstd-compressed FASTA: {:?}", path))?,
+        )
+    } else {
+        Box::new(reader)
+    };
+    Ok(decompressed)
+}
+
+/// Streams a reference FASTA one line at a time, transparently decompressing gzip/bgzip/xz/zstd
+/// input, and never buffers more than a single line of a contig's sequence at once. Modeled on
+/// needletail's streaming parser design, in place of `noodles::fasta::Reader`'s whole-record
+/// buffering.
+struct SequenceSource {
+    path: PathBuf,
+    reader: Box<dyn BufRead>,
+    pending_header: Option<String>,
+    line: String,
+}
+
+/// Parses the contig name out of a FASTA header line (without its leading `>` or trailing
+/// newline): the first whitespace-delimited token.
+fn parse_header_name(header_line: &str) -> String {
+    header_line
+        .trim_start_matches('>')
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_string()
+}
+
+impl SequenceSource {
+    fn open(path: &Path) -> Result<SequenceSource> {
+        let mut reader = open_decompressed(path)?;
+        let pending_header = Self::read_until_header(&mut reader, path)?;
+        Ok(SequenceSource {
+            path: path.to_path_buf(),
+            reader,
+            pending_header,
+            line: String::new(),
+        })
+    }
+
+    /// Reads lines until (and including) the next FASTA header, returning its name, or `None` if
+    /// the source is exhausted first.
+    fn read_until_header(reader: &mut Box<dyn BufRead>, path: &Path) -> Result<Option<String>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let bytes_read = reader
+                .read_line(&mut line)
+                .with_context(|| format!("Could not read FASTA: {:?}", path))?;
+            if bytes_read == 0 {
+                return Ok(None);
+            }
+            if line.starts_with('>') {
+                return Ok(Some(parse_header_name(&line)));
+            }
+        }
+    }
+
+    /// Returns the name of the next contig, or `None` once the source is exhausted.
+    fn next_contig(&mut self) -> Result<Option<String>> {
+        Ok(self.pending_header.take())
+    }
+
+    /// Streams the bases of the current contig to `on_base`, one line at a time, stopping at the
+    /// next header line (which becomes the name returned by the following [`Self::next_contig`])
+    /// or at EOF.
+    fn for_each_base(&mut self, mut on_base: impl FnMut(u8)) -> Result<()> {
+        loop {
+            self.line.clear();
+            let bytes_read = self
+                .reader
+                .read_line(&mut self.line)
+                .with_context(|| format!("Could not read FASTA: {:?}", self.path))?;
+            if bytes_read == 0 {
+                self.pending_header = None;
+                return Ok(());
+            }
+            if self.line.starts_with('>') {
+                self.pending_header = Some(parse_header_name(&self.line));
+                return Ok(());
+            }
+            for &base in self.line.trim_end_matches(['\r', '\n']).as_bytes() {
+                on_base(base);
+            }
+        }
+    }
+}
+
+/// A repeat that has passed all size/purity filters, carrying every field needed to emit it in
+/// any of [`OutputFormat`]'s column layouts without the consumer having to re-derive the unit or
+/// copy number by parsing a packed name field.
+struct ScoredRepeat {
+    contig: String,
+    start_position: Position,
+    end_position: Position,
+    unit: String,
+    copy_number: usize,
+    span: usize,
+    purity: f64,
+}
+
+/// Converts the given repeat to a [`ScoredRepeat`] if the repeat passes all the filters, otherwise
 /// None.
-fn to_bed_record(
+fn to_scored_repeat(
     opts: &Opts,
     contig: &str,
     position: usize,
     finder: &KmerFinder,
     repeat: &Repeat,
-) -> Option<bed::Record<4>> {
+) -> Option<ScoredRepeat> {
     if repeat.span < opts.min_bases
         || opts.max_bases < repeat.span
         || repeat.span / finder.len() < opts.min_repeats
@@ -143,33 +480,146 @@ fn to_bed_record(
     {
         None
     } else {
-        let unit = String::from_utf8_lossy(&repeat.kmer);
-        let name = format!("({}){:?}", unit, repeat.span / finder.len());
         let start_position = Position::try_from(position - repeat.span)
             .with_context(|| {
-                format!("Could not create BED start Position: {}", position - repeat.span)
+                format!(
+                    "Could not create BED start Position: {}",
+                    position - repeat.span
+                )
             })
             .unwrap();
         let end_position = Position::try_from(position - 1)
             .with_context(|| format!("Could not create BED end Position: {}", position - 1))
             .unwrap();
-        let name = bed::record::Name::from_str(&name)
-            .with_context(|| format!("Could not create BED name: {}", name))
-            .unwrap();
-        let bed_record = bed::Record::<4>::builder()
-            .set_reference_sequence_name(contig)
-            .set_start_position(start_position)
-            .set_end_position(end_position)
-            .set_name(name)
-            .build()
-            .with_context(|| {
-                format!(
-                    "Could not build a BED record at {}:{} for repeat {:?}",
-                    contig, position, repeat
+        Some(ScoredRepeat {
+            contig: contig.to_string(),
+            start_position,
+            end_position,
+            unit: String::from_utf8_lossy(&repeat.kmer).into_owned(),
+            copy_number: repeat.span / finder.len(),
+            span: repeat.span,
+            purity: repeat.purity,
+        })
+    }
+}
+
+/// Writes called microsatellites in the column layout selected by [`Opts::output_format`].
+enum RepeatWriter {
+    Bed4(bed::Writer<Box<dyn Write>>),
+    Bed6(bed::Writer<Box<dyn Write>>),
+    Tsv(Box<dyn Write>),
+}
+
+impl RepeatWriter {
+    fn open(opts: &Opts, io: &Io) -> Result<RepeatWriter> {
+        match opts.output_format {
+            OutputFormat::Bed4 => {
+                let writer = io
+                    .new_writer(&opts.output)
+                    .map(bed::Writer::new)
+                    .with_context(|| {
+                        format!("Could not open BED for writing: {:?}", opts.output)
+                    })?;
+                Ok(RepeatWriter::Bed4(writer))
+            }
+            OutputFormat::Bed6 => {
+                let writer = io
+                    .new_writer(&opts.output)
+                    .map(bed::Writer::new)
+                    .with_context(|| {
+                        format!("Could not open BED for writing: {:?}", opts.output)
+                    })?;
+                Ok(RepeatWriter::Bed6(writer))
+            }
+            OutputFormat::Tsv => {
+                let mut writer = io.new_writer(&opts.output).with_context(|| {
+                    format!("Could not open TSV for writing: {:?}", opts.output)
+                })?;
+                writeln!(
+                    writer,
+                    "contig\tstart\tend\tunit\tcopy_number\tspan\tpurity"
                 )
-            })
-            .unwrap();
-        Some(bed_record)
+                .with_context(|| "Could not write TSV header")
+                .unwrap();
+                Ok(RepeatWriter::Tsv(writer))
+            }
+        }
+    }
+
+    /// Writes a called repeat. Panics (consistent with the rest of this module) if the output
+    /// cannot be written to.
+    fn write(&mut self, scored: &ScoredRepeat) {
+        match self {
+            RepeatWriter::Bed4(writer) => {
+                let name = format!("({}){}", scored.unit, scored.copy_number);
+                let record = bed::Record::<4>::builder()
+                    .set_reference_sequence_name(&scored.contig)
+                    .set_start_position(scored.start_position)
+                    .set_end_position(scored.end_position)
+                    .set_name(
+                        bed::record::Name::from_str(&name)
+                            .with_context(|| format!("Could not create BED name: {}", name))
+                            .unwrap(),
+                    )
+                    .build()
+                    .with_context(|| format!("Could not build a BED record: {:?}", scored.contig))
+                    .unwrap();
+                writer
+                    .write_record(&record)
+                    .with_context(|| format!("Could not write BED record {:?}", record))
+                    .unwrap();
+            }
+            RepeatWriter::Bed6(writer) => {
+                let score = (scored.purity * 1000.0).round() as u16;
+                let record = bed::Record::<6>::builder()
+                    .set_reference_sequence_name(&scored.contig)
+                    .set_start_position(scored.start_position)
+                    .set_end_position(scored.end_position)
+                    .set_name(
+                        bed::record::Name::from_str(&scored.unit)
+                            .with_context(|| format!("Could not create BED name: {}", scored.unit))
+                            .unwrap(),
+                    )
+                    .set_score(
+                        bed::record::Score::try_from(score)
+                            .with_context(|| format!("Could not create BED score: {}", score))
+                            .unwrap(),
+                    )
+                    .set_strand(bed::record::Strand::None)
+                    .build()
+                    .with_context(|| format!("Could not build a BED record: {:?}", scored.contig))
+                    .unwrap();
+                writer
+                    .write_record(&record)
+                    .with_context(|| format!("Could not write BED record {:?}", record))
+                    .unwrap();
+            }
+            RepeatWriter::Tsv(writer) => {
+                writeln!(
+                    writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{:.4}",
+                    scored.contig,
+                    scored.start_position,
+                    scored.end_position,
+                    scored.unit,
+                    scored.copy_number,
+                    scored.span,
+                    scored.purity
+                )
+                .with_context(|| "Could not write TSV record")
+                .unwrap();
+            }
+        }
+    }
+}
+
+/// Upper-cases `base` if it is an unambiguous `A`/`C`/`G`/`T` call (in either case); returns `None`
+/// for `N`, an IUPAC ambiguity code, or any other byte, so callers can terminate repeat tracking at
+/// the position instead of silently treating it as a real base.
+fn normalize_base(base: u8) -> Option<u8> {
+    match base.to_ascii_uppercase() {
+        upper @ (b'A' | b'C' | b'G' | b'T') => Some(upper),
+        _ => None,
     }
 }
 
@@ -209,7 +659,7 @@ pub fn setup() -> Opts {
 }
 
 /// Stores information about a repeat that has been found.  T
-#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Repeat {
     /// The bases of the repeat
     pub kmer: Vec<u8>,
@@ -218,11 +668,14 @@ pub struct Repeat {
     /// The span of the repeat.  The repeat may span a few extra bases if the kmer is not
     /// repeated exactly.  Eg. a kmer of ACG could be seen twice ACGACGA so has span 7.
     pub span: usize,
+    /// Fraction of bases in `span` that matched the canonical repeat unit; 1.0 for a perfectly
+    /// pure tract, lower as tolerated mismatches accumulate.
+    pub purity: f64,
 }
 
 /// Struct to help find repeats of a given size when a contiguous sequence is provided one base
 /// at a time.
-#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct KmerFinder {
     // The current set of bases seen of a given size
     pub kmer: Vec<u8>,
@@ -230,11 +683,28 @@ pub struct KmerFinder {
     index: usize,
     // The span of the current repeat
     span: usize,
+    // The number of mismatching bases tolerated so far in the current repeat
+    mismatches: usize,
+    // The length of the current run of consecutive mismatching bases
+    consecutive_mismatches: usize,
+    // The maximum run of consecutive mismatching bases tolerated before the repeat is terminated
+    max_gap: usize,
+    // The maximum fraction of mismatching bases (mismatches / span) tolerated before the repeat is
+    // terminated
+    max_impurity: f64,
 }
 
 impl KmerFinder {
-    fn new(size: usize) -> KmerFinder {
-        KmerFinder { kmer: vec![b'n'; size], index: 0, span: 0 }
+    fn new(size: usize, max_gap: usize, max_impurity: f64) -> KmerFinder {
+        KmerFinder {
+            kmer: vec![b'n'; size],
+            index: 0,
+            span: 0,
+            mismatches: 0,
+            consecutive_mismatches: 0,
+            max_gap,
+            max_impurity,
+        }
     }
 
     fn len(&self) -> usize {
@@ -246,27 +716,50 @@ impl KmerFinder {
     /// Conceptually for a repeat with unit length N, stores up to the last N bases.  For each new
     /// base provided to [`KmerFinder::add`], if the span is smaller than the unit length the next
     /// base is updated, otherwise the new base is compared to the previous base at the appropriate
-    /// offset in the repeat.  In the latter case, if the base matches, the span is incremented and
-    /// None is returned, otherwise the repeat up to this point is returned and the kmer finder
-    /// reset to a new kmer that includes the new base as the last base.
+    /// offset in the repeat.  In the latter case, if the base matches, the span is incremented,
+    /// the consecutive-mismatch run is cleared, and None is returned.  If the base does not match,
+    /// it is tolerated (the expected base is kept and the base is counted as a mismatch) as long as
+    /// neither `max_gap` nor `max_impurity` would be exceeded; otherwise the repeat up to this
+    /// point is emitted (with any trailing mismatches trimmed) and the kmer finder is reset to a
+    /// new kmer that includes the new base as the last base.
     fn add_maybe_emit(&mut self, base: u8, emit: bool) -> Option<Repeat> {
         let retval = if self.span < self.len() {
-            // Not enough bases added yet, so update the kmer
+            // Not enough bases added yet, so update the kmer. The priming bases never count as
+            // mismatches.
             self.kmer[self.index] = base;
             None
         } else if self.kmer[self.index] == base {
             // The given base matches the expected base
+            self.consecutive_mismatches = 0;
             None
         } else {
-            // The given base does not match the expected base, so return the current repeat (if
-            // long enough).  Reset the span to the unit length to start a new repeat.
-            let repeat = if emit { self.emit() } else { None };
-            self.span = self.len() - 1; // span is incremented below, so subtract one here
-            self.kmer[self.index] = base;
-            repeat
+            let next_mismatches = self.mismatches + 1;
+            let next_consecutive = self.consecutive_mismatches + 1;
+            let next_impurity = next_mismatches as f64 / (self.span + 1) as f64;
+            if next_consecutive > self.max_gap || next_impurity > self.max_impurity {
+                // Exceeds the mismatch budget: emit the repeat up to (but not including) this
+                // base, trimming any trailing mismatches already tolerated, then start a new
+                // repeat with this base as its first (expected) base.
+                let repeat = if emit { self.emit() } else { None };
+                self.span = self.len() - 1; // span is incremented below, so subtract one here
+                self.mismatches = 0;
+                self.consecutive_mismatches = 0;
+                self.kmer[self.index] = base;
+                repeat
+            } else {
+                // Tolerate the mismatch: keep the expected base in the kmer (so the canonical unit
+                // doesn't drift) and extend the span through it.
+                self.mismatches = next_mismatches;
+                self.consecutive_mismatches = next_consecutive;
+                None
+            }
         };
         // increment index and span
-        self.index = if self.index == self.len() - 1 { 0 } else { self.index + 1 };
+        self.index = if self.index == self.len() - 1 {
+            0
+        } else {
+            self.index + 1
+        };
         self.span += 1;
         retval
     }
@@ -277,6 +770,8 @@ impl KmerFinder {
     }
 
     /// Emit the current repeat seen so far.  Returns None if not enough bases have been added.
+    /// Trailing mismatched bases (the current run of `consecutive_mismatches`) are trimmed from
+    /// the reported span before `num_repeats` and `purity` are computed.
     fn emit(&self) -> Option<Repeat> {
         if self.span < self.len() {
             None
@@ -296,9 +791,18 @@ impl KmerFinder {
                 i += 1;
                 j = if j == self.len() - 1 { 0 } else { j + 1 };
             }
-            let num_repeats = self.span / self.len();
 
-            Some(Repeat { kmer, num_repeats, span: self.span })
+            let span = self.span - self.consecutive_mismatches;
+            let num_repeats = span / self.len();
+            let trimmed_mismatches = self.mismatches - self.consecutive_mismatches;
+            let purity = 1.0 - (trimmed_mismatches as f64 / span as f64);
+
+            Some(Repeat {
+                kmer,
+                num_repeats,
+                span,
+                purity,
+            })
         }
     }
 
@@ -307,30 +811,42 @@ impl KmerFinder {
         self.kmer = vec![b'n'; self.len()];
         self.index = 0;
         self.span = 0;
+        self.mismatches = 0;
+        self.consecutive_mismatches = 0;
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::is_repeat;
+    use super::open_decompressed;
     use super::run;
+    use super::scan_contig;
     use super::KmerFinder;
     use super::Opts;
+    use super::OutputFormat;
     use super::Repeat;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use noodles::bed;
     use noodles::fasta;
     use rstest::rstest;
+    use std::io::Read;
+    use std::path::PathBuf;
     use std::{
         fs::File,
-        io::{BufReader, BufWriter},
+        io::{BufReader, BufWriter, Write as _},
     };
     use tempfile::tempdir;
+    use xz2::write::XzEncoder;
+    use zstd::stream::write::Encoder as ZstdEncoder;
 
     /// Helper function to verify values in [`Repeat`]
-    fn check_repeat(repeat: Repeat, kmer: &str, num_repeats: usize, span: usize) {
+    fn check_repeat(repeat: Repeat, kmer: &str, num_repeats: usize, span: usize, purity: f64) {
         assert_eq!(String::from_utf8(repeat.kmer).unwrap(), kmer);
         assert_eq!(repeat.num_repeats, num_repeats);
         assert_eq!(repeat.span, span);
+        assert!((repeat.purity - purity).abs() < f64::EPSILON);
     }
 
     #[rstest]
@@ -350,60 +866,155 @@ mod test {
     #[test]
     fn test_emit() {
         // too few bases seen to emit a repeat
-        assert_eq!(KmerFinder::new(3).emit(), None);
+        assert_eq!(KmerFinder::new(3, 0, 0.0).emit(), None);
         assert_eq!(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 1, span: 1 }.emit(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 1,
+                span: 1,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0
+            }
+            .emit(),
             None
         );
         assert_eq!(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 2, span: 2 }.emit(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 2,
+                span: 2,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0
+            }
+            .emit(),
             None
         );
 
         // emit repeats
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 0, span: 3 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 0,
+                span: 3,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "acg",
             1,
             3,
+            1.0,
         );
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 1, span: 4 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 1,
+                span: 4,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "acg",
             1,
             4,
+            1.0,
         );
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 2, span: 5 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 2,
+                span: 5,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "acg",
             1,
             5,
+            1.0,
         );
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 0, span: 6 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 0,
+                span: 6,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "acg",
             2,
             6,
+            1.0,
         );
 
         // emit repeats repeats with various indexes, but the same span
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 0, span: 5 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 0,
+                span: 5,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "cga",
             1,
             5,
+            1.0,
         );
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 1, span: 5 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 1,
+                span: 5,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "gac",
             1,
             5,
+            1.0,
         );
         check_repeat(
-            KmerFinder { kmer: [b'a', b'c', b'g'].to_vec(), index: 2, span: 5 }.emit().unwrap(),
+            KmerFinder {
+                kmer: [b'a', b'c', b'g'].to_vec(),
+                index: 2,
+                span: 5,
+                mismatches: 0,
+                consecutive_mismatches: 0,
+                max_gap: 0,
+                max_impurity: 0.0,
+            }
+            .emit()
+            .unwrap(),
             "acg",
             1,
             5,
+            1.0,
         );
     }
 
@@ -418,7 +1029,7 @@ mod test {
     #[case(8)]
     #[case(9)]
     fn test_simple_add(#[case] kmer_len: usize) {
-        let mut finder = KmerFinder::new(kmer_len);
+        let mut finder = KmerFinder::new(kmer_len, 0, 0.0);
 
         let mut i = 0;
         while i < kmer_len {
@@ -428,14 +1039,20 @@ mod test {
             }
             i += 1;
         }
-        check_repeat(finder.emit().unwrap(), &"a".repeat(kmer_len), 1, kmer_len);
+        check_repeat(
+            finder.emit().unwrap(),
+            &"a".repeat(kmer_len),
+            1,
+            kmer_len,
+            1.0,
+        );
     }
 
     /// Tests sequential calls to add looking for a trinucleotide repeat.  Also checks emit to
     /// verify the in progress repeat.
     #[test]
     fn test_trinuc() {
-        let mut finder = KmerFinder::new(3);
+        let mut finder = KmerFinder::new(3, 0, 0.0);
 
         // add the tri-nuc
         assert_eq!(finder.add(b'a'), None);
@@ -443,7 +1060,7 @@ mod test {
         assert_eq!(finder.add(b'c'), None);
         assert_eq!(finder.emit(), None);
         assert_eq!(finder.add(b'g'), None);
-        check_repeat(finder.emit().unwrap(), "acg", 1, 3);
+        check_repeat(finder.emit().unwrap(), "acg", 1, 3, 1.0);
 
         // add a few more copies
         let mut num_repeats = 1;
@@ -451,25 +1068,25 @@ mod test {
         while num_repeats <= 10 {
             assert_eq!(finder.add(b'a'), None);
             span += 1;
-            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span);
+            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span, 1.0);
             assert_eq!(finder.add(b'c'), None);
             span += 1;
-            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span);
+            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span, 1.0);
             assert_eq!(finder.add(b'g'), None);
             num_repeats += 1;
             span += 1;
-            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span);
+            check_repeat(finder.emit().unwrap(), "acg", num_repeats, span, 1.0);
         }
 
         // add one more base, so not a fully new trinuc repeat
         assert_eq!(finder.add(b'a'), None);
         span += 1;
-        check_repeat(finder.emit().unwrap(), "acg", num_repeats, span);
+        check_repeat(finder.emit().unwrap(), "acg", num_repeats, span, 1.0);
 
         // add a mismatching base, which should yields the previous repeat
-        check_repeat(finder.add(b't').unwrap(), "acg", num_repeats, span);
+        check_repeat(finder.add(b't').unwrap(), "acg", num_repeats, span, 1.0);
         // emitting the current repeat, yields a new one, with one copy (atg tga gat)
-        check_repeat(finder.emit().unwrap(), "gat", 1, 3);
+        check_repeat(finder.emit().unwrap(), "gat", 1, 3, 1.0);
     }
 
     /// Helper method to create a new [`fasta::Record`]
@@ -501,19 +1118,34 @@ mod test {
             min_repeats: 3,
             min_repeat_length: 2,
             max_repeat_length: 5,
+            permissive_bases: false,
+            max_impurity: 0.0,
+            max_gap: 0,
+            output_format: OutputFormat::Bed4,
+            threads: 1,
         };
 
         // Write a fasta
         {
-            let mut fasta_writer: fasta::Writer<BufWriter<File>> =
-                File::create(in_fasta).map(BufWriter::new).map(fasta::Writer::new).unwrap();
+            let mut fasta_writer: fasta::Writer<BufWriter<File>> = File::create(in_fasta)
+                .map(BufWriter::new)
+                .map(fasta::Writer::new)
+                .unwrap();
 
             // add contigs that will have no repeats returned
             to_contig("too_few_bases", &"ACG".repeat(3), &mut fasta_writer);
             to_contig("too_many_bases", &"ACG".repeat(34), &mut fasta_writer);
             to_contig("too_few_repeats", &"AGGAT".repeat(2), &mut fasta_writer);
-            to_contig("too_small_repeat_length", &"A".repeat(15), &mut fasta_writer);
-            to_contig("too_large_repeat_length", &"ACGTGA".repeat(2), &mut fasta_writer);
+            to_contig(
+                "too_small_repeat_length",
+                &"A".repeat(15),
+                &mut fasta_writer,
+            );
+            to_contig(
+                "too_large_repeat_length",
+                &"ACGTGA".repeat(2),
+                &mut fasta_writer,
+            );
 
             // // add contigs with repeats on the parameter boundaries
             to_contig("(CG)5", &"CG".repeat(5), &mut fasta_writer); // min_bases and min_repeat_length
@@ -534,9 +1166,15 @@ mod test {
         run(&opts).unwrap();
 
         // Read in the output bED
-        let mut bed_reader = File::open(out_bed).map(BufReader::new).map(bed::Reader::new).unwrap();
-        let records: Vec<bed::Record<4>> =
-            bed_reader.records::<4>().map(std::result::Result::unwrap).into_iter().collect();
+        let mut bed_reader = File::open(out_bed)
+            .map(BufReader::new)
+            .map(bed::Reader::new)
+            .unwrap();
+        let records: Vec<bed::Record<4>> = bed_reader
+            .records::<4>()
+            .map(std::result::Result::unwrap)
+            .into_iter()
+            .collect();
 
         // Check the records
         assert_eq!(records.len(), 6);
@@ -547,4 +1185,275 @@ mod test {
         check_bed(&records[4], "complicated", 12, 31, "(CG)10");
         check_bed(&records[5], "complicated", 46, 60, "(TGGAT)3");
     }
+
+    /// Runs [`run`] with the given `threads` count over a fixed multi-contig FASTA (one contig per
+    /// [`KmerFinder`] call site's worth of repeats, so the worker pool has several jobs in flight at
+    /// once) and returns the resulting BED records.
+    fn run_repeat_finder(threads: usize) -> Vec<bed::Record<4>> {
+        let tempdir = tempdir().unwrap();
+        let in_fasta = tempdir.path().join("input.fasta");
+        let out_bed = tempdir.path().join("output.bed");
+        let opts = Opts {
+            input: in_fasta.clone(),
+            output: out_bed.clone(),
+            min_bases: 10,
+            max_bases: 20,
+            min_repeats: 3,
+            min_repeat_length: 2,
+            max_repeat_length: 5,
+            permissive_bases: false,
+            max_impurity: 0.0,
+            max_gap: 0,
+            output_format: OutputFormat::Bed4,
+            threads,
+        };
+
+        {
+            let mut fasta_writer: fasta::Writer<BufWriter<File>> = File::create(in_fasta)
+                .map(BufWriter::new)
+                .map(fasta::Writer::new)
+                .unwrap();
+
+            // A handful of contigs, each with a differently-positioned repeat, so that under
+            // `threads > 1` more than one worker has a job in flight and contigs can plausibly
+            // finish out of dispatch order.
+            to_contig("contig_0", &"CG".repeat(10), &mut fasta_writer);
+            to_contig("contig_1", &"AGGAT".repeat(3), &mut fasta_writer);
+            to_contig("contig_2", &"ACG".repeat(3), &mut fasta_writer); // no repeat: too few bases
+            to_contig("contig_3", &"TGGAT".repeat(4), &mut fasta_writer);
+            to_contig("contig_4", &"CG".repeat(6), &mut fasta_writer);
+            to_contig("contig_5", &"AGGAT".repeat(5), &mut fasta_writer);
+        }
+
+        run(&opts).unwrap();
+
+        File::open(out_bed)
+            .map(BufReader::new)
+            .map(bed::Reader::new)
+            .unwrap()
+            .records::<4>()
+            .map(std::result::Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_repeat_finder_multithreaded_matches_single_threaded_order() {
+        let single_threaded = run_repeat_finder(1);
+        let multi_threaded = run_repeat_finder(4);
+
+        // Sanity check that the fixture actually produced output worth comparing.
+        assert_eq!(single_threaded.len(), 4);
+
+        assert_eq!(single_threaded.len(), multi_threaded.len());
+        for (single, multi) in single_threaded.iter().zip(multi_threaded.iter()) {
+            assert_eq!(
+                single.reference_sequence_name(),
+                multi.reference_sequence_name()
+            );
+            assert_eq!(single.start_position(), multi.start_position());
+            assert_eq!(single.end_position(), multi.end_position());
+            assert_eq!(single.name(), multi.name());
+        }
+    }
+
+    /// Reads back the line(s) written for `output_format` as raw tab-separated text, sidestepping
+    /// the column-layout-specific `noodles::bed` reader so both `Bed6` and `Tsv` can be checked with
+    /// the same helper.
+    fn run_and_read_lines(output_format: OutputFormat) -> Vec<Vec<String>> {
+        let tempdir = tempdir().unwrap();
+        let in_fasta = tempdir.path().join("input.fasta");
+        let out = tempdir.path().join("output");
+        let opts = Opts {
+            input: in_fasta.clone(),
+            output: out.clone(),
+            min_bases: 10,
+            max_bases: 20,
+            min_repeats: 3,
+            min_repeat_length: 2,
+            max_repeat_length: 5,
+            permissive_bases: false,
+            max_impurity: 0.0,
+            max_gap: 0,
+            output_format,
+            threads: 1,
+        };
+
+        {
+            let mut fasta_writer: fasta::Writer<BufWriter<File>> = File::create(in_fasta)
+                .map(BufWriter::new)
+                .map(fasta::Writer::new)
+                .unwrap();
+            to_contig("(CG)10", &"CG".repeat(10), &mut fasta_writer);
+        }
+
+        run(&opts).unwrap();
+
+        std::fs::read_to_string(out)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('\t').map(str::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_repeat_finder_bed6_output() {
+        let lines = run_and_read_lines(OutputFormat::Bed6);
+
+        assert_eq!(lines.len(), 1);
+        let fields = &lines[0];
+        assert_eq!(fields[0], "(CG)10"); // contig
+        assert_eq!(fields[1], "0"); // start (0-based, half-open)
+        assert_eq!(fields[2], "20"); // end
+        assert_eq!(fields[3], "CG"); // name: unit only, no copy number
+        assert_eq!(fields[4], "1000"); // score: purity 1.0 scaled to the BED score range
+        assert_eq!(fields[5], "."); // strand: unspecified
+    }
+
+    #[test]
+    fn test_repeat_finder_tsv_output() {
+        let lines = run_and_read_lines(OutputFormat::Tsv);
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            vec![
+                "contig",
+                "start",
+                "end",
+                "unit",
+                "copy_number",
+                "span",
+                "purity"
+            ]
+        );
+        // Unlike the BED writers, the TSV writer `Display`s `start_position`/`end_position`
+        // directly without converting to BED's 0-based, half-open convention, so start is 1 here.
+        assert_eq!(
+            lines[1],
+            vec!["(CG)10", "1", "20", "CG", "10", "20", "1.0000"]
+        );
+    }
+
+    #[test]
+    fn test_open_decompressed_reads_gzip() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("input.fasta.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b">contig\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = open_decompressed(&path).unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, ">contig\nACGT\n");
+    }
+
+    #[test]
+    fn test_open_decompressed_reads_xz() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("input.fasta.xz");
+        let mut encoder = XzEncoder::new(File::create(&path).unwrap(), 6);
+        encoder.write_all(b">contig\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = open_decompressed(&path).unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, ">contig\nACGT\n");
+    }
+
+    #[test]
+    fn test_open_decompressed_reads_zstd() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("input.fasta.zst");
+        let mut encoder = ZstdEncoder::new(File::create(&path).unwrap(), 0).unwrap();
+        encoder.write_all(b">contig\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+
+        let mut reader = open_decompressed(&path).unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, ">contig\nACGT\n");
+    }
+
+    #[test]
+    fn test_open_decompressed_reads_plain_text() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("input.fasta");
+        std::fs::write(&path, b">contig\nACGT\n").unwrap();
+
+        let mut reader = open_decompressed(&path).unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, ">contig\nACGT\n");
+    }
+
+    #[test]
+    fn test_n_base_terminates_and_resets_repeat_tracking() {
+        let opts = Opts {
+            input: PathBuf::new(),
+            output: PathBuf::new(),
+            min_bases: 10,
+            max_bases: 20,
+            min_repeats: 3,
+            min_repeat_length: 2,
+            max_repeat_length: 5,
+            permissive_bases: false,
+            max_impurity: 0.0,
+            max_gap: 0,
+            output_format: OutputFormat::Bed4,
+            threads: 1,
+        };
+
+        // An `N` splits what would otherwise be a single 20bp `(CG)10` repeat into two separate
+        // `(CG)5` calls, one on each side of the ambiguous base.
+        let mut sequence = "CG".repeat(5);
+        sequence.push('N');
+        sequence.push_str(&"CG".repeat(5));
+
+        let records = scan_contig(&opts, "contig", sequence.as_bytes());
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].unit, "CG");
+        assert_eq!(records[0].copy_number, 5);
+        assert_eq!(records[0].span, 10);
+        assert_eq!(records[1].unit, "CG");
+        assert_eq!(records[1].copy_number, 5);
+        assert_eq!(records[1].span, 10);
+    }
+
+    #[test]
+    fn test_kmerfinder_tolerates_mismatch_within_gap_budget() {
+        let mut finder = KmerFinder::new(3, 1, 1.0);
+        for base in b"acgacgatgacg" {
+            assert_eq!(finder.add(*base), None);
+        }
+        check_repeat(finder.emit().unwrap(), "acg", 4, 12, 1.0 - 1.0 / 12.0);
+    }
+
+    #[test]
+    fn test_kmerfinder_trims_trailing_unconfirmed_mismatch_on_emit() {
+        let mut finder = KmerFinder::new(3, 1, 1.0);
+        // Same prefix as above, but stop right after the tolerated mismatch instead of
+        // confirming it with a following match: the trailing mismatch must not count towards
+        // `span`, `num_repeats`, or `purity`.
+        for base in b"acgacgat" {
+            assert_eq!(finder.add(*base), None);
+        }
+        check_repeat(finder.emit().unwrap(), "acg", 2, 7, 1.0);
+    }
+
+    #[test]
+    fn test_kmerfinder_impurity_budget_terminates_repeat() {
+        let mut finder = KmerFinder::new(3, 5, 0.1);
+        for base in b"acgacgacgacg" {
+            assert_eq!(finder.add(*base), None);
+        }
+        assert_eq!(finder.add(b't'), None); // tolerated: impurity 1/13 is under the 0.1 budget
+        assert_eq!(finder.add(b'c'), None); // matches, clearing the consecutive-mismatch run
+        // A second mismatch pushes impurity to 2/15 ≈ 0.133, over budget: the repeat up to (but
+        // not including) this base is emitted. The earlier mismatch was followed by a match, so
+        // it is not trailing: it stays in the span and lowers the purity.
+        check_repeat(finder.add(b'x').unwrap(), "acg", 4, 14, 1.0 - 1.0 / 14.0);
+    }
 }